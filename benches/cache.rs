@@ -0,0 +1,152 @@
+//! Compares the cache indexes under concurrent load: lookups that always hit
+//! as the number of readers doubles, and a mix of lookups and inserts on a
+//! miss like the server does.
+
+use std::{hint::black_box, time::Instant};
+
+use chrono::Duration;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rand::Rng;
+use rust_weather_api::{
+    cache::{CacheIndex, CacheValue, Lookup, SpatialCache, TierConfig},
+    entities::Location,
+};
+
+// entries preloaded into the tier.
+const ENTRIES: usize = 10_000;
+const MAX_THREADS: usize = 8;
+// queries are spread over the contiguous US.
+const LATITUDES: (f64, f64) = (25.0, 49.0);
+const LONGITUDES: (f64, f64) = (-125.0, -67.0);
+
+#[derive(Debug, Clone)]
+struct Reading(u32);
+
+impl CacheValue for Reading {
+    fn describe(&self) -> String {
+        self.0.to_string()
+    }
+}
+
+fn random_location(rng: &mut impl Rng) -> Location {
+    Location {
+        latitude: rng.gen_range(LATITUDES.0..LATITUDES.1),
+        longitude: rng.gen_range(LONGITUDES.0..LONGITUDES.1),
+    }
+}
+
+// a weather-sized tier preloaded with `ENTRIES` random entries, and where
+// they are.
+fn preloaded(index: CacheIndex) -> (SpatialCache<Reading>, Vec<Location>) {
+    let cache = SpatialCache::new(
+        "bench",
+        TierConfig {
+            radius_km: 10.0,
+            ttl: Duration::minutes(15),
+            stale_grace: Duration::hours(24),
+            index,
+            geohash_precision: 5,
+            shards: 16,
+        },
+        None,
+    );
+    let mut rng = rand::thread_rng();
+    let locations: Vec<Location> = (0..ENTRIES).map(|_| random_location(&mut rng)).collect();
+    for (value, location) in locations.iter().enumerate() {
+        cache.insert(
+            *location,
+            Reading(value as u32),
+            Duration::minutes(15),
+            None,
+        );
+    }
+    (cache, locations)
+}
+
+// 1, 2, 4, ... up to `MAX_THREADS`.
+fn thread_counts() -> impl Iterator<Item = usize> {
+    std::iter::successors(Some(1), |threads| Some(threads * 2))
+        .take_while(|threads| *threads <= MAX_THREADS)
+}
+
+/// Every lookup is a hit, so no reader ever takes a lock exclusively.
+fn concurrent_readers(criterion: &mut Criterion) {
+    let mut group = criterion.benchmark_group("concurrent_readers");
+    for index in [CacheIndex::KdTree, CacheIndex::Geohash] {
+        let (cache, locations) = preloaded(index);
+        for readers in thread_counts() {
+            // one lookup per reader per iteration.
+            group.throughput(Throughput::Elements(readers as u64));
+            group.bench_with_input(
+                BenchmarkId::new(format!("{:?}", index), readers),
+                &readers,
+                |bencher, &readers| {
+                    bencher.iter_custom(|iters| {
+                        let start = Instant::now();
+                        std::thread::scope(|scope| {
+                            for reader in 0..readers {
+                                let (cache, locations) = (&cache, &locations);
+                                scope.spawn(move || {
+                                    for i in 0..iters as usize {
+                                        let location =
+                                            &locations[(i * readers + reader) % locations.len()];
+                                        black_box(cache.get(location, |_| true));
+                                    }
+                                });
+                            }
+                        });
+                        start.elapsed()
+                    })
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+/// Random lookups, inserting on a miss, so writers contend with readers.
+fn lookup_or_insert(criterion: &mut Criterion) {
+    let mut group = criterion.benchmark_group("lookup_or_insert");
+    for index in [CacheIndex::KdTree, CacheIndex::Geohash] {
+        let (cache, _) = preloaded(index);
+        for threads in thread_counts() {
+            group.throughput(Throughput::Elements(threads as u64));
+            group.bench_with_input(
+                BenchmarkId::new(format!("{:?}", index), threads),
+                &threads,
+                |bencher, &threads| {
+                    bencher.iter_custom(|iters| {
+                        let start = Instant::now();
+                        std::thread::scope(|scope| {
+                            for _ in 0..threads {
+                                let cache = &cache;
+                                scope.spawn(move || {
+                                    let mut rng = rand::thread_rng();
+                                    for _ in 0..iters {
+                                        let location = random_location(&mut rng);
+                                        if !matches!(
+                                            cache.get(&location, |_| true),
+                                            Lookup::Fresh(_)
+                                        ) {
+                                            cache.insert(
+                                                location,
+                                                Reading(0),
+                                                Duration::minutes(15),
+                                                None,
+                                            );
+                                        }
+                                    }
+                                });
+                            }
+                        });
+                        start.elapsed()
+                    })
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, concurrent_readers, lookup_or_insert);
+criterion_main!(benches);
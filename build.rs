@@ -5,6 +5,7 @@ fn main() {
         .protoc()
         .include("proto")
         .input("proto/weather_message.proto")
+        .input("proto/cache_snapshot.proto")
         .cargo_out_dir("proto")
         .customize(Customize::default().gen_mod_rs(true).generate_getter(true))
         .run_from_script();
@@ -0,0 +1,270 @@
+use std::collections::BTreeMap;
+
+use actix_web::{
+    delete, get,
+    http::StatusCode,
+    post,
+    web::{self, Query},
+    HttpResponse, Responder,
+};
+use chrono::Utc;
+use protobuf::Message;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{
+    auth::middleware::AdminAuth,
+    cache::{CacheValue, EntrySummary, SpatialCache, Tier},
+    entities::Location,
+    errors::{rejected, IntoHttpError, IntoUpstreamHttpError},
+    snapshot, validation,
+    weather::{entities::Units, methods::refresh_weather, utils::haversine},
+    weather_proto::cache_snapshot::CacheSnapshot,
+    AppState,
+};
+
+// snapshots of a warm cache are far larger than the default payload limit.
+const MAX_SNAPSHOT_BYTES: usize = 256 * 1024 * 1024;
+
+pub(crate) fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/admin")
+            .wrap(AdminAuth)
+            .service(upstream_breakers)
+            .service(upstream_usage)
+            .service(upstream_keys)
+            .service(list_cache)
+            .service(lookup_cache)
+            .service(invalidate_entry)
+            .service(invalidate_cache)
+            .service(refresh_cache)
+            .service(
+                web::resource("/cache/snapshot")
+                    .app_data(web::PayloadConfig::new(MAX_SNAPSHOT_BYTES))
+                    .route(web::get().to(export_snapshot))
+                    .route(web::post().to(import_snapshot)),
+            ),
+    );
+}
+
+#[get("/upstream/breakers")]
+async fn upstream_breakers(data: web::Data<AppState>) -> impl Responder {
+    web::Json(data.http_client.breakers.statuses())
+}
+
+#[get("/upstream/usage")]
+async fn upstream_usage(data: web::Data<AppState>) -> impl Responder {
+    web::Json(data.http_client.budget.usage())
+}
+
+#[get("/upstream/keys")]
+async fn upstream_keys(data: web::Data<AppState>) -> impl Responder {
+    web::Json(data.http_client.keys.statuses())
+}
+
+#[derive(Debug, Deserialize)]
+struct PointQuery {
+    lat: f64,
+    lon: f64,
+    units: String,
+}
+
+impl PointQuery {
+    fn location(&self) -> actix_web::Result<Location> {
+        validation::location(self.lat, self.lon)
+    }
+
+    fn units(&self) -> actix_web::Result<Units> {
+        validation::units(&self.units)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TierQuery {
+    // every tier when not given.
+    tier: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct InvalidateQuery {
+    tier: Option<String>,
+    lat: Option<f64>,
+    lon: Option<f64>,
+    radius_km: Option<f64>,
+}
+
+/// Lists the entries of every tier, or only of `tier`.
+#[get("/cache")]
+async fn list_cache(
+    query: Query<TierQuery>,
+    data: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    let entries: BTreeMap<&str, Vec<EntrySummary>> = selected_tiers(&data, query.tier.as_deref())?
+        .into_iter()
+        .map(|tier| (tier.name(), tier.summaries()))
+        .collect();
+    Ok(HttpResponse::Ok().json(entries))
+}
+
+/// Shows, for each tier, the entry that would serve a request for `lat`, `lon`
+/// and `units`.
+#[get("/cache/lookup")]
+async fn lookup_cache(
+    query: Query<PointQuery>,
+    data: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    let location = query.location()?;
+    let units = query.units()?;
+    let caches = &data.caches;
+    let found = json!({
+        "weather": serving_entry(&caches.weather, &location, |forecast| forecast.serves(units, false)),
+        "aqi": serving_entry(&caches.aqi, &location, |_| true),
+        "reverse_geocode": serving_entry(&caches.reverse_geocode, &location, |_| true),
+    });
+    if found.as_object().unwrap().values().all(Value::is_null) {
+        return Err(rejected(
+            "no cache entry serves this location",
+            StatusCode::NOT_FOUND,
+        ));
+    }
+    Ok(HttpResponse::Ok().json(found))
+}
+
+#[delete("/cache/{tier}/entries/{id}")]
+async fn invalidate_entry(
+    path: web::Path<(String, usize)>,
+    data: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    let (tier, id) = path.into_inner();
+    let removed =
+        selected_tiers(&data, Some(&tier))?[0].remove_where(&|entry_id, _| entry_id == id);
+    if removed == 0 {
+        return Err(rejected("no such cache entry", StatusCode::NOT_FOUND));
+    }
+    Ok(HttpResponse::Ok().json(json!({ "removed": removed })))
+}
+
+/// Removes every entry within `radius_km` of `lat`, `lon`, or every entry when
+/// no point is given, from `tier` or from all tiers.
+#[delete("/cache")]
+async fn invalidate_cache(
+    query: Query<InvalidateQuery>,
+    data: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    let tiers = selected_tiers(&data, query.tier.as_deref())?;
+    // everything when no area is given.
+    let area = match (query.lat, query.lon, query.radius_km) {
+        (None, None, None) => None,
+        (Some(lat), Some(lon), Some(radius_km)) => Some(([lat, lon], radius_km)),
+        _ => {
+            return Err(rejected(
+                "lat, lon and radius_km must be given together",
+                StatusCode::BAD_REQUEST,
+            ))
+        }
+    };
+    let predicate = |_, location: &Location| {
+        area.is_none_or(|(center, radius_km)| {
+            haversine(&center, &[location.latitude, location.longitude]) <= radius_km
+        })
+    };
+    let removed: usize = tiers.iter().map(|tier| tier.remove_where(&predicate)).sum();
+    Ok(HttpResponse::Ok().json(json!({ "removed": removed })))
+}
+
+/// Fetches the forecast for a location from OWM now, replacing the weather
+/// entry that served it.
+#[post("/cache/refresh")]
+async fn refresh_cache(
+    query: Query<PointQuery>,
+    data: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    let id = refresh_weather(query.location()?, query.units()?, &data)
+        .await
+        .http_upstream_error("could not refresh weather")?;
+    // the new entry may already have been invalidated by a concurrent request.
+    let entry = data
+        .caches
+        .weather
+        .summary(id)
+        .ok_or_else(|| rejected("entry was invalidated", StatusCode::CONFLICT))?;
+    Ok(HttpResponse::Ok().json(entry))
+}
+
+// the tier named `name`, or every tier.
+fn selected_tiers<'a>(
+    data: &'a AppState,
+    name: Option<&str>,
+) -> actix_web::Result<Vec<&'a dyn Tier>> {
+    match name {
+        None => Ok(data.caches.tiers().to_vec()),
+        Some(name) => data
+            .caches
+            .tier(name)
+            .map(|tier| vec![tier])
+            .ok_or_else(|| rejected("unknown cache tier", StatusCode::BAD_REQUEST)),
+    }
+}
+
+// the entry of `cache` that would serve `location`, with its distance.
+fn serving_entry<T: CacheValue>(
+    cache: &SpatialCache<T>,
+    location: &Location,
+    matches: impl Fn(&T) -> bool,
+) -> Option<Value> {
+    let (id, distance_km) = cache.find(location, matches)?;
+    let entry = cache.entry(id)?;
+    Some(json!({
+        "distance_km": distance_km,
+        "radius_km": cache.radius_at(location),
+        "fresh": entry.expiry > Utc::now(),
+        "entry": cache.summarize(id, &entry),
+    }))
+}
+
+async fn export_snapshot(data: web::Data<AppState>) -> actix_web::Result<HttpResponse> {
+    let bytes = snapshot::take_snapshot(&data)
+        .write_to_bytes()
+        .http_internal_error("could not encode snapshot")?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/x-protobuf")
+        .body(bytes))
+}
+
+async fn import_snapshot(
+    body: web::Bytes,
+    data: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    let snapshot = CacheSnapshot::parse_from_bytes(&body)
+        .http_error("body is not a cache snapshot", StatusCode::BAD_REQUEST)?;
+    let imported = snapshot::restore_snapshot(&data, snapshot)
+        .http_error("snapshot has invalid entries", StatusCode::BAD_REQUEST)?;
+    Ok(HttpResponse::Ok().json(json!({ "imported": imported })))
+}
+
+#[actix_web::test]
+async fn test_admin_api_needs_a_key_without_client_auth() {
+    use actix_web::{test, App};
+
+    let mut config = crate::config::Config::default();
+    config.owm.api_key = String::from("owm-key");
+    config.auth.enabled = false;
+    let app = test::init_service(
+        App::new()
+            .app_data(crate::test_state(config))
+            .configure(configure),
+    )
+    .await;
+    for request in [
+        test::TestRequest::get().uri("/admin/upstream/keys"),
+        test::TestRequest::delete().uri("/admin/cache"),
+        test::TestRequest::get()
+            .uri("/admin/upstream/keys")
+            .insert_header((crate::auth::middleware::ADMIN_KEY_HEADER, "guess")),
+    ] {
+        let err = test::try_call_service(&app, request.to_request())
+            .await
+            .unwrap_err();
+        assert_eq!(err.error_response().status(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,337 @@
+use std::{
+    future::{ready, Future, Ready},
+    pin::Pin,
+};
+
+use actix_web::{
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    error,
+    http::header::{HeaderMap, HeaderName, HeaderValue, RETRY_AFTER},
+    web, Error, HttpMessage, HttpResponse,
+};
+
+use crate::{entities::CacheStatus, AppState};
+
+use super::{ratelimit::RateLimitInfo, ClientIdentity};
+
+// clients send their key in this header, or in the `api_key` query parameter.
+const API_KEY_HEADER: &str = "X-API-Key";
+const API_KEY_PARAM: &str = "api_key";
+// admin keys are only accepted in a header, query strings end up in access logs.
+pub(crate) const ADMIN_KEY_HEADER: &str = "X-Admin-Key";
+
+/// Rejects requests without a valid client API key. Wraps the `/v1/api` and
+/// `/v2` scopes.
+pub(crate) struct ClientAuth;
+
+impl<S, B> Transform<S, ServiceRequest> for ClientAuth
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = ClientAuthMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ClientAuthMiddleware { service }))
+    }
+}
+
+pub(crate) struct ClientAuthMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for ClientAuthMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let data = req
+            .app_data::<web::Data<AppState>>()
+            .expect("AppState is registered")
+            .clone();
+        if data.config.auth.enabled {
+            let path = req.path();
+            let endpoint = path
+                .strip_prefix("/v1/api/")
+                .or_else(|| path.strip_prefix("/v2/"))
+                .unwrap_or(path)
+                .split('/')
+                .next()
+                .unwrap_or_default();
+            match data
+                .clients
+                .authenticate(api_key(&req).as_deref(), endpoint)
+            {
+                Ok(identity) => {
+                    tracing::debug!("Authenticated client {}", identity.name);
+                    req.extensions_mut().insert(identity);
+                }
+                Err(err) => {
+                    tracing::info!("Rejected request to {}: {}", req.path(), err.message());
+                    let err = error::InternalError::new(err.message(), err.status_code());
+                    return Box::pin(ready(Err(err.into())));
+                }
+            }
+        }
+        Box::pin(self.service.call(req))
+    }
+}
+
+/// Rejects requests without a valid admin key. Wraps the `/admin` scope.
+pub(crate) struct AdminAuth;
+
+impl<S, B> Transform<S, ServiceRequest> for AdminAuth
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = AdminAuthMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AdminAuthMiddleware { service }))
+    }
+}
+
+pub(crate) struct AdminAuthMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for AdminAuthMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let data = req
+            .app_data::<web::Data<AppState>>()
+            .expect("AppState is registered")
+            .clone();
+        // checked even with client auth disabled; the admin API is never open.
+        let key = req
+            .headers()
+            .get(ADMIN_KEY_HEADER)
+            .and_then(|value| value.to_str().ok());
+        if let Err(err) = data.clients.authenticate_admin(key) {
+            tracing::warn!(
+                "Rejected admin request to {}: {}",
+                req.path(),
+                err.message()
+            );
+            let err = error::InternalError::new(err.message(), err.status_code());
+            return Box::pin(ready(Err(err.into())));
+        }
+        Box::pin(self.service.call(req))
+    }
+}
+
+/// Token-bucket rate limiting per client and per IP. Every request is charged
+/// the cache hit cost up front and the difference once it turns out to be a miss.
+/// Wraps the `/v1/api` and `/v2` scopes inside `ClientAuth`.
+pub(crate) struct RateLimit;
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RateLimitMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware { service }))
+    }
+}
+
+pub(crate) struct RateLimitMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let data = req
+            .app_data::<web::Data<AppState>>()
+            .expect("AppState is registered")
+            .clone();
+        let limits = &data.config.rate_limit;
+        if !limits.enabled {
+            return Box::pin(self.service.call(req));
+        }
+
+        let ip = client_ip(&req, limits.trust_proxy_headers);
+        let client = req
+            .extensions()
+            .get::<ClientIdentity>()
+            .map(|identity| identity.name.clone());
+        let upfront = limits.cache_hit_cost;
+
+        // ClientAuth charged the request to the client's daily quota, which a
+        // request that is not served should not use up.
+        let reject = |info| {
+            if let Some(name) = &client {
+                data.clients.refund(name);
+            }
+            Box::pin(ready(Err(too_many_requests(info))))
+        };
+        let ip_info = match data.ip_limiter.try_take(&ip, upfront) {
+            Ok(info) => info,
+            Err(info) => return reject(info),
+        };
+        // the headers describe whichever bucket is closer to running out.
+        let info = match &client {
+            Some(name) => match data.key_limiter.try_take(name, upfront) {
+                Ok(key_info) if key_info.remaining <= ip_info.remaining => key_info,
+                Ok(_) => ip_info,
+                Err(info) => {
+                    // the request is not served, so give the IP its tokens back.
+                    data.ip_limiter.charge(&ip, -upfront);
+                    return reject(info);
+                }
+            },
+            None => ip_info,
+        };
+
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let mut res = fut.await?;
+            let limits = &data.config.rate_limit;
+            let extra = limits.cache_miss_cost - limits.cache_hit_cost;
+            let missed =
+                res.request().extensions().get::<CacheStatus>() == Some(&CacheStatus::Miss);
+            if missed && extra > 0.0 {
+                data.ip_limiter.charge(&ip, extra);
+                if let Some(name) = &client {
+                    data.key_limiter.charge(name, extra);
+                }
+            }
+            insert_rate_limit_headers(res.headers_mut(), &info);
+            Ok(res)
+        })
+    }
+}
+
+fn client_ip(req: &ServiceRequest, trust_proxy_headers: bool) -> String {
+    if trust_proxy_headers {
+        if let Some(ip) = req.connection_info().realip_remote_addr() {
+            return ip.to_string();
+        }
+    }
+    req.peer_addr()
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_default()
+}
+
+fn insert_rate_limit_headers(headers: &mut HeaderMap, info: &RateLimitInfo) {
+    for (name, value) in [
+        ("x-ratelimit-limit", info.limit),
+        ("x-ratelimit-remaining", info.remaining),
+        ("x-ratelimit-reset", info.reset_secs),
+    ] {
+        headers.insert(HeaderName::from_static(name), HeaderValue::from(value));
+    }
+    if let Some(retry_after) = info.retry_after_secs {
+        headers.insert(RETRY_AFTER, HeaderValue::from(retry_after));
+    }
+}
+
+fn too_many_requests(info: RateLimitInfo) -> Error {
+    tracing::info!(
+        "Rate limited request, retry after {:?}s",
+        info.retry_after_secs
+    );
+    let mut response = HttpResponse::TooManyRequests().body("rate limit exceeded");
+    insert_rate_limit_headers(response.headers_mut(), &info);
+    error::InternalError::from_response("rate limit exceeded", response).into()
+}
+
+fn api_key(req: &ServiceRequest) -> Option<String> {
+    if let Some(header) = req.headers().get(API_KEY_HEADER) {
+        return header.to_str().ok().map(String::from);
+    }
+    web::Query::<Vec<(String, String)>>::from_query(req.query_string())
+        .ok()?
+        .into_inner()
+        .into_iter()
+        .find(|(name, _)| name == API_KEY_PARAM)
+        .map(|(_, value)| value)
+}
+
+#[actix_web::test]
+async fn test_rate_limited_requests_leave_the_quota_alone() {
+    use actix_web::{http::StatusCode, test, App};
+
+    let mut config = crate::config::Config::default();
+    config.owm.api_key = String::from("owm-key");
+    config.auth.clients = vec![super::ClientConfig {
+        name: String::from("widget"),
+        key: String::from("widget-key"),
+        allowed_endpoints: vec![],
+        daily_quota: Some(5),
+    }];
+    config.rate_limit.per_key.capacity = 1.0;
+    config.rate_limit.per_key.refill_per_sec = 0.0;
+    let data = crate::test_state(config);
+    let app = test::init_service(
+        App::new().app_data(data.clone()).service(
+            web::scope("/v1/api")
+                .wrap(RateLimit)
+                .wrap(ClientAuth)
+                .route("/weather", web::get().to(|| async { "sunny" })),
+        ),
+    )
+    .await;
+    let request = || {
+        test::TestRequest::get()
+            .uri("/v1/api/weather")
+            .insert_header((API_KEY_HEADER, "widget-key"))
+            .to_request()
+    };
+
+    assert!(test::call_service(&app, request())
+        .await
+        .status()
+        .is_success());
+    let err = test::try_call_service(&app, request()).await.unwrap_err();
+    assert_eq!(err.error_response().status(), StatusCode::TOO_MANY_REQUESTS);
+    let usage = data.clients.usage.lock().unwrap();
+    assert_eq!(usage["widget"].1, 1);
+}
@@ -0,0 +1,221 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use actix_web::http::StatusCode;
+use chrono::{NaiveDate, Utc};
+use serde::Deserialize;
+
+use crate::redact::Redactor;
+
+pub(crate) mod middleware;
+pub(crate) mod ratelimit;
+
+// route groups under /v1/api and /v2 a client can be allowed to call.
+pub(crate) const API_ENDPOINTS: [&str; 3] = ["weather", "geocode", "reversegeocode"];
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ClientConfig {
+    pub(crate) name: String,
+    pub(crate) key: String,
+    // empty means every endpoint.
+    #[serde(default)]
+    pub(crate) allowed_endpoints: Vec<String>,
+    #[serde(default)]
+    pub(crate) daily_quota: Option<u64>,
+}
+
+/// The client a request was authenticated as, stored in the request extensions.
+#[derive(Debug, Clone)]
+pub(crate) struct ClientIdentity {
+    pub(crate) name: String,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum AuthError {
+    MissingKey,
+    InvalidKey,
+    EndpointNotAllowed,
+    QuotaExceeded,
+}
+
+impl AuthError {
+    pub(crate) fn status_code(&self) -> StatusCode {
+        match self {
+            AuthError::MissingKey | AuthError::InvalidKey => StatusCode::UNAUTHORIZED,
+            AuthError::EndpointNotAllowed => StatusCode::FORBIDDEN,
+            AuthError::QuotaExceeded => StatusCode::TOO_MANY_REQUESTS,
+        }
+    }
+
+    pub(crate) fn message(&self) -> &'static str {
+        match self {
+            AuthError::MissingKey => "missing API key",
+            AuthError::InvalidKey => "invalid API key",
+            AuthError::EndpointNotAllowed => "API key is not allowed to call this endpoint",
+            AuthError::QuotaExceeded => "API key has used up its daily quota",
+        }
+    }
+}
+
+/// The configured client and admin keys and how many requests each client
+/// made today.
+#[derive(Debug)]
+pub(crate) struct ClientStore {
+    clients: Vec<ClientConfig>,
+    admin_keys: Vec<String>,
+    usage: Mutex<HashMap<String, (NaiveDate, u64)>>,
+}
+
+impl ClientStore {
+    pub(crate) fn new(clients: Vec<ClientConfig>, admin_keys: Vec<String>) -> Self {
+        let redactor = Redactor::global();
+        redactor.add_secrets(clients.iter().map(|client| client.key.clone()));
+        redactor.add_secrets(admin_keys.iter().cloned());
+        Self {
+            clients,
+            admin_keys,
+            usage: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Checks `key` against the admin keys. With none configured every request
+    /// is rejected.
+    pub(crate) fn authenticate_admin(&self, key: Option<&str>) -> Result<(), AuthError> {
+        let key = key
+            .filter(|key| !key.is_empty())
+            .ok_or(AuthError::MissingKey)?;
+        let matched = self.admin_keys.iter().fold(false, |matched, admin_key| {
+            constant_time_eq(admin_key.as_bytes(), key.as_bytes()) | matched
+        });
+        if matched {
+            Ok(())
+        } else {
+            Err(AuthError::InvalidKey)
+        }
+    }
+
+    /// Checks `key` against the store and charges the request to its quota.
+    pub(crate) fn authenticate(
+        &self,
+        key: Option<&str>,
+        endpoint: &str,
+    ) -> Result<ClientIdentity, AuthError> {
+        let key = key
+            .filter(|key| !key.is_empty())
+            .ok_or(AuthError::MissingKey)?;
+        // compare against every client so the lookup time does not depend on which key matched.
+        let client = self
+            .clients
+            .iter()
+            .fold(None, |found, client| {
+                if constant_time_eq(client.key.as_bytes(), key.as_bytes()) {
+                    Some(client)
+                } else {
+                    found
+                }
+            })
+            .ok_or(AuthError::InvalidKey)?;
+
+        if !client.allowed_endpoints.is_empty()
+            && !client
+                .allowed_endpoints
+                .iter()
+                .any(|allowed| allowed == endpoint)
+        {
+            return Err(AuthError::EndpointNotAllowed);
+        }
+
+        let today = Utc::now().date_naive();
+        let mut usage = self.usage.lock().unwrap();
+        let (day, count) = usage.entry(client.name.clone()).or_insert((today, 0));
+        if *day != today {
+            *day = today;
+            *count = 0;
+        }
+        if client.daily_quota.is_some_and(|quota| *count >= quota) {
+            return Err(AuthError::QuotaExceeded);
+        }
+        *count += 1;
+
+        Ok(ClientIdentity {
+            name: client.name.clone(),
+        })
+    }
+
+    /// Gives back the request `authenticate` charged to the quota of the
+    /// client `name`, for requests turned away before they were served.
+    pub(crate) fn refund(&self, name: &str) {
+        let today = Utc::now().date_naive();
+        let mut usage = self.usage.lock().unwrap();
+        if let Some((day, count)) = usage.get_mut(name) {
+            if *day == today {
+                *count = count.saturating_sub(1);
+            }
+        }
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[test]
+fn test_authenticate() {
+    let store = ClientStore::new(
+        vec![
+            ClientConfig {
+                name: String::from("widget"),
+                key: String::from("widget-key"),
+                allowed_endpoints: vec![String::from("weather")],
+                daily_quota: Some(1),
+            },
+            ClientConfig {
+                name: String::from("app"),
+                key: String::from("app-key"),
+                allowed_endpoints: vec![],
+                daily_quota: None,
+            },
+        ],
+        vec![String::from("admin-key")],
+    );
+    assert_eq!(
+        store.authenticate(None, "weather").unwrap_err(),
+        AuthError::MissingKey
+    );
+    assert_eq!(
+        store.authenticate(Some("nope"), "weather").unwrap_err(),
+        AuthError::InvalidKey
+    );
+    assert_eq!(
+        store
+            .authenticate(Some("widget-key"), "geocode")
+            .unwrap_err(),
+        AuthError::EndpointNotAllowed
+    );
+    assert_eq!(
+        store
+            .authenticate(Some("widget-key"), "weather")
+            .unwrap()
+            .name,
+        "widget"
+    );
+    assert_eq!(
+        store
+            .authenticate(Some("widget-key"), "weather")
+            .unwrap_err(),
+        AuthError::QuotaExceeded
+    );
+    assert!(store.authenticate(Some("app-key"), "geocode").is_ok());
+
+    assert!(store.authenticate_admin(Some("admin-key")).is_ok());
+    assert_eq!(
+        store.authenticate_admin(Some("app-key")).unwrap_err(),
+        AuthError::InvalidKey
+    );
+    assert_eq!(
+        store
+            .authenticate(Some("admin-key"), "weather")
+            .unwrap_err(),
+        AuthError::InvalidKey
+    );
+}
@@ -0,0 +1,145 @@
+use std::{collections::HashMap, sync::Mutex, time::Instant};
+
+use serde::Deserialize;
+
+// once this many buckets exist, full (idle) ones are dropped.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct BucketConfig {
+    pub(crate) capacity: f64,
+    pub(crate) refill_per_sec: f64,
+}
+
+impl Default for BucketConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 60.0,
+            refill_per_sec: 1.0,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// The state of a bucket after a request, used for the X-RateLimit-* headers.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RateLimitInfo {
+    pub(crate) limit: u64,
+    pub(crate) remaining: u64,
+    // seconds until the bucket is full again.
+    pub(crate) reset_secs: u64,
+    // seconds until the rejected request would fit, set when it was rejected.
+    pub(crate) retry_after_secs: Option<u64>,
+}
+
+/// Token buckets keyed by client API key or IP address.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    config: BucketConfig,
+    buckets: Mutex<HashMap<String, TokenBucket>>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(config: BucketConfig) -> Self {
+        Self {
+            config,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes `cost` tokens from the bucket for `key` if it holds enough.
+    pub(crate) fn try_take(&self, key: &str, cost: f64) -> Result<RateLimitInfo, RateLimitInfo> {
+        self.try_take_at(key, cost, Instant::now())
+    }
+
+    fn try_take_at(
+        &self,
+        key: &str,
+        cost: f64,
+        now: Instant,
+    ) -> Result<RateLimitInfo, RateLimitInfo> {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= PRUNE_THRESHOLD {
+            buckets.retain(|_, bucket| self.refilled(bucket, now) < self.config.capacity);
+        }
+        let bucket = buckets.entry(key.to_string()).or_insert(TokenBucket {
+            tokens: self.config.capacity,
+            updated: now,
+        });
+        bucket.tokens = self.refilled(bucket, now);
+        bucket.updated = now;
+        if bucket.tokens >= cost {
+            bucket.tokens -= cost;
+            Ok(self.info(bucket.tokens, None))
+        } else {
+            let retry_after = self.secs_to_refill(cost - bucket.tokens);
+            Err(self.info(bucket.tokens, Some(retry_after)))
+        }
+    }
+
+    /// Charges (or with a negative cost, refunds) tokens after the fact. The
+    /// bucket may go into debt, which later requests pay off.
+    pub(crate) fn charge(&self, key: &str, cost: f64) {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if let Some(bucket) = buckets.get_mut(key) {
+            bucket.tokens = (self.refilled(bucket, now) - cost).min(self.config.capacity);
+            bucket.updated = now;
+        }
+    }
+
+    fn refilled(&self, bucket: &TokenBucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.config.refill_per_sec).min(self.config.capacity)
+    }
+
+    fn secs_to_refill(&self, tokens: f64) -> u64 {
+        (tokens.max(0.0) / self.config.refill_per_sec).ceil() as u64
+    }
+
+    fn info(&self, tokens: f64, retry_after_secs: Option<u64>) -> RateLimitInfo {
+        RateLimitInfo {
+            limit: self.config.capacity as u64,
+            remaining: tokens.max(0.0) as u64,
+            reset_secs: self.secs_to_refill(self.config.capacity - tokens),
+            retry_after_secs,
+        }
+    }
+}
+
+#[test]
+fn test_token_bucket_refills() {
+    let limiter = RateLimiter::new(BucketConfig {
+        capacity: 2.0,
+        refill_per_sec: 1.0,
+    });
+    let now = Instant::now();
+    assert_eq!(limiter.try_take_at("a", 1.0, now).unwrap().remaining, 1);
+    assert_eq!(limiter.try_take_at("a", 1.0, now).unwrap().remaining, 0);
+    let rejected = limiter.try_take_at("a", 1.0, now).unwrap_err();
+    assert_eq!(rejected.retry_after_secs, Some(1));
+    assert_eq!(rejected.reset_secs, 2);
+    // other keys have their own bucket.
+    assert!(limiter.try_take_at("b", 1.0, now).is_ok());
+    assert!(limiter
+        .try_take_at("a", 1.0, now + std::time::Duration::from_secs(1))
+        .is_ok());
+}
+
+#[test]
+fn test_cheap_requests_fit_where_expensive_ones_do_not() {
+    let limiter = RateLimiter::new(BucketConfig {
+        capacity: 1.0,
+        refill_per_sec: 0.1,
+    });
+    let now = Instant::now();
+    assert!(limiter.try_take_at("a", 0.5, now).is_ok());
+    assert!(limiter.try_take_at("a", 1.0, now).is_err());
+    assert!(limiter.try_take_at("a", 0.5, now).is_ok());
+}
@@ -0,0 +1,301 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        RwLock,
+    },
+};
+
+use crate::{entities::Location, weather::utils::haversine};
+
+use super::Cached;
+
+const BASE32: &[u8; 32] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+// km per degree of latitude.
+const KM_PER_DEGREE: f64 = 111.195;
+// searches spanning more cells than this scan every shard instead.
+const MAX_SEARCH_CELLS: usize = 4096;
+
+/// The geohash of `location` with `precision` characters. Nearby points share
+/// a prefix, and the same point always gets the same hash, so cells can key an
+/// external cache too.
+pub(crate) fn geohash(location: &Location, precision: usize) -> String {
+    let mut latitude = (-90.0, 90.0);
+    let mut longitude = (-180.0, 180.0);
+    let mut hash = String::with_capacity(precision);
+    let (mut bits, mut char_index, mut even) = (0, 0, true);
+    while hash.len() < precision {
+        // bits alternate between longitude and latitude, starting with longitude.
+        let (range, value) = if even {
+            (&mut longitude, location.longitude)
+        } else {
+            (&mut latitude, location.latitude)
+        };
+        let mid = (range.0 + range.1) / 2.0;
+        char_index <<= 1;
+        if value >= mid {
+            char_index |= 1;
+            range.0 = mid;
+        } else {
+            range.1 = mid;
+        }
+        even = !even;
+        bits += 1;
+        if bits == 5 {
+            hash.push(BASE32[char_index] as char);
+            bits = 0;
+            char_index = 0;
+        }
+    }
+    hash
+}
+
+// a cell as its (row, column) in the grid of cells with the same precision.
+type Cell = (u32, u32);
+
+#[derive(Debug)]
+struct Shard<T> {
+    cells: HashMap<Cell, Vec<usize>>,
+    entries: HashMap<usize, Cached<T>>,
+}
+
+/// Entries bucketed by geohash cell, with the cells spread over independently
+/// locked shards. Lookups share the locks, and an insert only blocks lookups
+/// that touch its shard.
+#[derive(Debug)]
+pub(crate) struct GridStore<T> {
+    // size of the grid at the configured precision.
+    rows: u32,
+    columns: u32,
+    shards: Vec<RwLock<Shard<T>>>,
+    // ids are `sequence * shards + shard`, so the shard of an id is implied.
+    next_sequence: AtomicUsize,
+}
+
+impl<T: Clone> GridStore<T> {
+    pub(crate) fn new(precision: usize, shards: usize) -> Self {
+        // geohash bits alternate starting with longitude.
+        let bits = 5 * precision as u32;
+        Self {
+            rows: 1 << (bits / 2),
+            columns: 1 << bits.div_ceil(2),
+            shards: (0..shards.max(1))
+                .map(|_| {
+                    RwLock::new(Shard {
+                        cells: HashMap::new(),
+                        entries: HashMap::new(),
+                    })
+                })
+                .collect(),
+            next_sequence: AtomicUsize::new(1),
+        }
+    }
+
+    // height and width of a cell in degrees.
+    fn cell_size(&self) -> (f64, f64) {
+        (180.0 / self.rows as f64, 360.0 / self.columns as f64)
+    }
+
+    // the cell holding `location`, the one its geohash names.
+    fn cell(&self, location: &Location) -> Cell {
+        let (height, width) = self.cell_size();
+        let row = ((location.latitude + 90.0) / height) as u32;
+        let column = ((location.longitude + 180.0) / width) as u32;
+        (row.min(self.rows - 1), column.min(self.columns - 1))
+    }
+
+    fn shard_of_cell(&self, cell: Cell) -> usize {
+        let mut hasher = DefaultHasher::new();
+        cell.hash(&mut hasher);
+        hasher.finish() as usize % self.shards.len()
+    }
+
+    // the cells that may hold entries within `radius_km` of `location`, or None
+    // if there are too many to be worth listing.
+    fn search_cells(&self, location: &Location, radius_km: f64) -> Option<Vec<Cell>> {
+        let (_, width) = self.cell_size();
+        let latitude_span = radius_km / KM_PER_DEGREE;
+        let cos = location.latitude.to_radians().cos();
+        let longitude_span = if cos > 1e-6 {
+            (latitude_span / cos).min(180.0)
+        } else {
+            180.0
+        };
+        let (first_row, _) = self.cell(&Location {
+            latitude: (location.latitude - latitude_span).max(-90.0),
+            longitude: 0.0,
+        });
+        let (last_row, _) = self.cell(&Location {
+            latitude: (location.latitude + latitude_span).min(90.0),
+            longitude: 0.0,
+        });
+        let first_column = ((location.longitude - longitude_span + 180.0) / width).floor() as i64;
+        let last_column = ((location.longitude + longitude_span + 180.0) / width).floor() as i64;
+        let columns = ((last_column - first_column + 1) as u32).min(self.columns);
+        let count = (last_row - first_row + 1) as usize * columns as usize;
+        if count > MAX_SEARCH_CELLS {
+            return None;
+        }
+        let mut cells = Vec::with_capacity(count);
+        for row in first_row..=last_row {
+            for column in 0..columns {
+                // wrap around the antimeridian.
+                let column = (first_column + column as i64).rem_euclid(self.columns as i64);
+                cells.push((row, column as u32));
+            }
+        }
+        Some(cells)
+    }
+
+    // calls `visit` with the id and location of every entry that may lie within
+    // `radius_km` of `location`, locking one shard at a time.
+    fn candidates(
+        &self,
+        location: &Location,
+        radius_km: f64,
+        mut visit: impl FnMut(usize, &Location),
+    ) {
+        match self.search_cells(location, radius_km) {
+            Some(cells) => {
+                let mut cells: Vec<(usize, Cell)> = cells
+                    .into_iter()
+                    .map(|cell| (self.shard_of_cell(cell), cell))
+                    .collect();
+                cells.sort_unstable();
+                for by_shard in cells.chunk_by(|(a, _), (b, _)| a == b) {
+                    let shard = self.shards[by_shard[0].0].read().unwrap();
+                    for id in by_shard
+                        .iter()
+                        .filter_map(|(_, cell)| shard.cells.get(cell))
+                        .flatten()
+                    {
+                        visit(*id, &shard.entries[id].location);
+                    }
+                }
+            }
+            None => {
+                for shard in &self.shards {
+                    let shard = shard.read().unwrap();
+                    for (id, entry) in &shard.entries {
+                        visit(*id, &entry.location);
+                    }
+                }
+            }
+        }
+    }
+
+    pub(crate) fn within(&self, location: &Location, radius_km: f64) -> Vec<(usize, f64)> {
+        let query = [location.latitude, location.longitude];
+        let mut found = vec![];
+        self.candidates(location, radius_km, |id, cached| {
+            let dist = haversine(&query, &[cached.latitude, cached.longitude]);
+            if dist <= radius_km {
+                found.push((id, dist));
+            }
+        });
+        found.sort_by(|(_, a), (_, b)| a.total_cmp(b));
+        found
+    }
+
+    pub(crate) fn read<R>(&self, id: usize, f: impl FnOnce(&Cached<T>) -> R) -> Option<R> {
+        let shard = self.shards[id % self.shards.len()].read().unwrap();
+        shard.entries.get(&id).map(f)
+    }
+
+    fn remove(&self, id: usize) {
+        let mut shard = self.shards[id % self.shards.len()].write().unwrap();
+        self.remove_from(&mut shard, id);
+    }
+
+    pub(crate) fn insert(&self, entry: Cached<T>, replaces: Option<usize>) -> usize {
+        if let Some(id) = replaces {
+            self.remove(id);
+        }
+        let cell = self.cell(&entry.location);
+        let shard_index = self.shard_of_cell(cell);
+        let id =
+            self.next_sequence.fetch_add(1, Ordering::Relaxed) * self.shards.len() + shard_index;
+        let mut shard = self.shards[shard_index].write().unwrap();
+        shard.cells.entry(cell).or_default().push(id);
+        shard.entries.insert(id, entry);
+        id
+    }
+
+    pub(crate) fn remove_where(&self, predicate: &dyn Fn(usize, &Cached<T>) -> bool) -> usize {
+        let mut removed = 0;
+        for shard in &self.shards {
+            let mut shard = shard.write().unwrap();
+            let ids: Vec<usize> = shard
+                .entries
+                .iter()
+                .filter(|(id, entry)| predicate(**id, entry))
+                .map(|(id, _)| *id)
+                .collect();
+            for id in &ids {
+                self.remove_from(&mut shard, *id);
+            }
+            removed += ids.len();
+        }
+        removed
+    }
+
+    pub(crate) fn entries(&self) -> Vec<(usize, Cached<T>)> {
+        self.shards
+            .iter()
+            .flat_map(|shard| {
+                let shard = shard.read().unwrap();
+                shard
+                    .entries
+                    .iter()
+                    .map(|(id, entry)| (*id, entry.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.read().unwrap().entries.len())
+            .sum()
+    }
+
+    pub(crate) fn is_reachable(&self) -> bool {
+        self.shards.iter().all(|shard| shard.read().is_ok())
+    }
+
+    fn remove_from(&self, shard: &mut Shard<T>, id: usize) {
+        let Some(entry) = shard.entries.remove(&id) else {
+            return;
+        };
+        let cell = self.cell(&entry.location);
+        if let Some(ids) = shard.cells.get_mut(&cell) {
+            ids.retain(|other| *other != id);
+            if ids.is_empty() {
+                shard.cells.remove(&cell);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_geohash_cells() {
+    assert_eq!(
+        geohash(&Location::at(57.64911, 10.40744), 11),
+        "u4pruydqqvj"
+    );
+    assert_eq!(geohash(&Location::at(37.7749, -122.4194), 5), "9q8yy");
+
+    // a search around the antimeridian includes cells on both sides.
+    let store = GridStore::<()>::new(5, 4);
+    let cells = store
+        .search_cells(&Location::at(0.0, 179.99), 10.0)
+        .unwrap();
+    assert!(cells.contains(&store.cell(&Location::at(0.0, 179.99))));
+    assert!(cells.contains(&store.cell(&Location::at(0.0, -179.99))));
+    // near a pole every longitude is close.
+    assert!(store
+        .search_cells(&Location::at(89.99, 0.0), 10.0)
+        .is_none());
+}
@@ -0,0 +1,175 @@
+use std::{collections::HashMap, sync::RwLock};
+
+use kiddo::{distance::squared_euclidean, float::kdtree::KdTree};
+
+use crate::{
+    entities::Location,
+    weather::utils::{jitter, km_to_squared_chord, squared_chord_to_km, unit_sphere_point},
+};
+
+use super::{CacheTree, Cached};
+
+fn point(location: &Location) -> [f64; 3] {
+    unit_sphere_point(location.latitude, location.longitude)
+}
+
+// where entry `id` is kept in the tree, so that entries at one spot or on one
+// latitude never fill a bucket kiddo cannot split.
+fn entry_point(location: &Location, id: usize) -> [f64; 3] {
+    jitter(point(location), id)
+}
+
+#[derive(Debug)]
+struct Entries<T> {
+    kdtree: CacheTree,
+    entries: HashMap<usize, Cached<T>>,
+    next_id: usize,
+}
+
+impl<T> Entries<T> {
+    fn remove(&mut self, id: usize) -> Option<Cached<T>> {
+        let entry = self.entries.remove(&id)?;
+        self.kdtree.remove(&entry_point(&entry.location, id), id);
+        Some(entry)
+    }
+
+    fn insert(&mut self, entry: Cached<T>) -> usize {
+        self.next_id += 1;
+        let id = self.next_id;
+        self.kdtree.add(&entry_point(&entry.location, id), id);
+        self.entries.insert(id, entry);
+        id
+    }
+}
+
+/// Entries indexed by a single kd-tree. Lookups share the lock, only inserts
+/// and removals take it exclusively.
+#[derive(Debug)]
+pub(crate) struct KdTreeStore<T> {
+    entries: RwLock<Entries<T>>,
+}
+
+impl<T: Clone> KdTreeStore<T> {
+    pub(crate) fn new() -> Self {
+        Self {
+            entries: RwLock::new(Entries {
+                kdtree: KdTree::new(),
+                entries: HashMap::new(),
+                next_id: 0,
+            }),
+        }
+    }
+
+    pub(crate) fn within(&self, location: &Location, radius_km: f64) -> Vec<(usize, f64)> {
+        let entries = self.entries.read().unwrap();
+        if entries.entries.is_empty() {
+            return vec![];
+        }
+        entries
+            .kdtree
+            .within(
+                &point(location),
+                km_to_squared_chord(radius_km),
+                &squared_euclidean,
+            )
+            .into_iter()
+            .map(|neighbour| (neighbour.item, squared_chord_to_km(neighbour.distance)))
+            .collect()
+    }
+
+    pub(crate) fn read<R>(&self, id: usize, f: impl FnOnce(&Cached<T>) -> R) -> Option<R> {
+        self.entries.read().unwrap().entries.get(&id).map(f)
+    }
+
+    pub(crate) fn insert(&self, entry: Cached<T>, replaces: Option<usize>) -> usize {
+        let mut entries = self.entries.write().unwrap();
+        if let Some(id) = replaces {
+            entries.remove(id);
+        }
+        entries.insert(entry)
+    }
+
+    pub(crate) fn remove_where(&self, predicate: &dyn Fn(usize, &Cached<T>) -> bool) -> usize {
+        let mut entries = self.entries.write().unwrap();
+        let ids: Vec<usize> = entries
+            .entries
+            .iter()
+            .filter(|(id, entry)| predicate(**id, entry))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in &ids {
+            entries.remove(*id);
+        }
+        ids.len()
+    }
+
+    pub(crate) fn entries(&self) -> Vec<(usize, Cached<T>)> {
+        let entries = self.entries.read().unwrap();
+        entries
+            .entries
+            .iter()
+            .map(|(id, entry)| (*id, entry.clone()))
+            .collect()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries.read().unwrap().entries.len()
+    }
+
+    pub(crate) fn is_reachable(&self) -> bool {
+        self.entries.read().is_ok()
+    }
+}
+
+#[test]
+fn test_nearest_across_antimeridian_and_poles() {
+    let store = KdTreeStore::<()>::new();
+    let cached = |location| super::Cached {
+        value: (),
+        location,
+        fetched: chrono::Utc::now(),
+        expiry: chrono::Utc::now(),
+        hits: Default::default(),
+    };
+    let east = store.insert(cached(Location::at(0.0, 179.95)), None);
+    let far = store.insert(cached(Location::at(0.0, 179.0)), None);
+    let arctic = store.insert(cached(Location::at(89.9, -90.0)), None);
+    store.insert(cached(Location::at(88.0, 90.0)), None);
+
+    // about 11km away on the other side of the antimeridian.
+    let within = store.within(&Location::at(0.0, -179.95), 150.0);
+    assert_eq!(within[0].0, east);
+    assert!((within[0].1 - 11.12).abs() < 0.01);
+    assert_eq!(within[1].0, far);
+    assert_eq!(within.len(), 2);
+
+    // longitudes converge near the pole: 89.9,90 is 22km from 89.9,-90 but
+    // about 222km from 88,90.
+    let within = store.within(&Location::at(89.9, 90.0), 50.0);
+    assert_eq!(within.len(), 1);
+    assert_eq!(within[0].0, arctic);
+    assert!((within[0].1 - 22.24).abs() < 0.01);
+    assert!(store.within(&Location::at(-89.9, 0.0), 50.0).is_empty());
+}
+
+#[test]
+fn test_many_entries_at_one_spot() {
+    let store = KdTreeStore::<()>::new();
+    let at = Location::at(37.5, -122.0);
+    // more than a bucket holds, all sharing every coordinate.
+    for _ in 0..100 {
+        store.insert(
+            super::Cached {
+                value: (),
+                location: at,
+                fetched: chrono::Utc::now(),
+                expiry: chrono::Utc::now(),
+                hits: Default::default(),
+            },
+            None,
+        );
+    }
+    assert_eq!(store.within(&at, 1.0).len(), 100);
+    assert_eq!(store.remove_where(&|id, _| id % 2 == 0), 50);
+    assert_eq!(store.within(&at, 1.0).len(), 50);
+}
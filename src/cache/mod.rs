@@ -0,0 +1,700 @@
+pub(crate) mod grid;
+pub(crate) mod kdtree;
+pub mod radius;
+
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use chrono::{DateTime, Duration, Utc};
+use kiddo::float::kdtree::KdTree;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, trace, warn, Span};
+
+use crate::{
+    config::CacheConfig,
+    entities::{CacheStatus, Location},
+    geocoding::entities::ReverseGeocode,
+    metrics::Metrics,
+    upstream,
+    weather::entities::CachedForecast,
+};
+
+use self::{
+    grid::{geohash, GridStore},
+    kdtree::KdTreeStore,
+    radius::RadiusModel,
+};
+
+// entries this close to a new value are checked for one it replaces.
+const SAME_POINT_KM: f64 = 0.001;
+
+/// Names of the cache tiers, as used in metrics, config and the admin API.
+pub(crate) const TIER_NAMES: [&str; 3] = ["weather", "aqi", "reverse_geocode"];
+
+/// Maps unit sphere points, see `unit_sphere_point`, to entry ids. Distances
+/// are squared chords.
+pub(crate) type CacheTree = KdTree<f64, usize, 3, 32, u32>;
+
+/// How a tier finds the entries near a query.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheIndex {
+    // one kd-tree per tier behind a single lock.
+    #[default]
+    KdTree,
+    // entries bucketed by geohash cell over independently locked shards.
+    Geohash,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TierConfig {
+    // entries within this distance of a query are reused.
+    pub radius_km: f64,
+    pub ttl: Duration,
+    // expired entries are kept this long to be served while OWM is
+    // unavailable, then evicted.
+    pub stale_grace: Duration,
+    pub index: CacheIndex,
+    // length of the geohash cells used by the geohash index and in entry keys.
+    pub geohash_precision: usize,
+    pub shards: usize,
+}
+
+/// A value cached for the location it was fetched for.
+#[derive(Debug, Clone)]
+pub(crate) struct Cached<T> {
+    pub(crate) value: T,
+    pub(crate) location: Location,
+    pub(crate) fetched: DateTime<Utc>,
+    pub(crate) expiry: DateTime<Utc>,
+    // requests served from this entry.
+    pub(crate) hits: Hits,
+}
+
+/// A request counter that can be bumped while the entry is only read locked.
+#[derive(Debug, Default)]
+pub(crate) struct Hits(AtomicU64);
+
+impl Hits {
+    pub(crate) fn new(hits: u64) -> Self {
+        Self(AtomicU64::new(hits))
+    }
+
+    pub(crate) fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn add(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Clone for Hits {
+    fn clone(&self) -> Self {
+        Self::new(self.get())
+    }
+}
+
+/// The result of a cache lookup.
+#[derive(Debug)]
+pub enum Lookup<T> {
+    Fresh(T),
+    // expired, but still served while OWM is unavailable. The entry with this
+    // id is replaced once the value is fetched again.
+    Stale { id: usize, value: T },
+    Miss,
+}
+
+/// What a fetch passed to `SpatialCache::get_or_fetch` produced.
+pub(crate) enum Fetched<T> {
+    // cache the value for the given TTL.
+    Cache(T, Duration),
+    // serve the value without caching it, e.g. an empty upstream answer.
+    Uncached(T),
+}
+
+/// Values stored in a cache tier.
+pub trait CacheValue: Clone {
+    /// A short human readable summary for the admin API.
+    fn describe(&self) -> String;
+
+    /// What besides the location distinguishes entries in the cache key, if
+    /// anything.
+    fn key_part(&self) -> Option<String> {
+        None
+    }
+}
+
+/// The spatial index behind a tier.
+#[derive(Debug)]
+enum Store<T> {
+    KdTree(KdTreeStore<T>),
+    Geohash(GridStore<T>),
+}
+
+impl<T: Clone> Store<T> {
+    // entries within `radius_km`, nearest first.
+    fn within(&self, location: &Location, radius_km: f64) -> Vec<(usize, f64)> {
+        match self {
+            Store::KdTree(store) => store.within(location, radius_km),
+            Store::Geohash(store) => store.within(location, radius_km),
+        }
+    }
+
+    fn read<R>(&self, id: usize, f: impl FnOnce(&Cached<T>) -> R) -> Option<R> {
+        match self {
+            Store::KdTree(store) => store.read(id, f),
+            Store::Geohash(store) => store.read(id, f),
+        }
+    }
+
+    fn insert(&self, entry: Cached<T>, replaces: Option<usize>) -> usize {
+        match self {
+            Store::KdTree(store) => store.insert(entry, replaces),
+            Store::Geohash(store) => store.insert(entry, replaces),
+        }
+    }
+
+    fn remove_where(&self, predicate: &dyn Fn(usize, &Cached<T>) -> bool) -> usize {
+        match self {
+            Store::KdTree(store) => store.remove_where(predicate),
+            Store::Geohash(store) => store.remove_where(predicate),
+        }
+    }
+
+    fn entries(&self) -> Vec<(usize, Cached<T>)> {
+        match self {
+            Store::KdTree(store) => store.entries(),
+            Store::Geohash(store) => store.entries(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Store::KdTree(store) => store.len(),
+            Store::Geohash(store) => store.len(),
+        }
+    }
+
+    fn is_reachable(&self) -> bool {
+        match self {
+            Store::KdTree(store) => store.is_reachable(),
+            Store::Geohash(store) => store.is_reachable(),
+        }
+    }
+}
+
+/// One type of upstream data cached by location, with its own spatial index,
+/// radius and TTL. A query is served by the nearest entry if it lies within the
+/// radius, which shrinks around both points when a `RadiusModel` is given.
+#[derive(Debug)]
+pub struct SpatialCache<T> {
+    // label used in metrics and the admin API.
+    name: &'static str,
+    config: TierConfig,
+    radius_model: Option<Arc<RadiusModel>>,
+    store: Store<T>,
+}
+
+impl<T: CacheValue> SpatialCache<T> {
+    pub fn new(
+        name: &'static str,
+        config: TierConfig,
+        radius_model: Option<Arc<RadiusModel>>,
+    ) -> Self {
+        Self {
+            name,
+            config,
+            radius_model,
+            store: match config.index {
+                CacheIndex::KdTree => Store::KdTree(KdTreeStore::new()),
+                CacheIndex::Geohash => {
+                    Store::Geohash(GridStore::new(config.geohash_precision, config.shards))
+                }
+            },
+        }
+    }
+
+    pub(crate) fn config(&self) -> TierConfig {
+        self.config
+    }
+
+    /// The cache radius in km at `location`.
+    pub(crate) fn radius_at(&self, location: &Location) -> f64 {
+        match &self.radius_model {
+            Some(model) => model.radius_km(self.config.radius_km, location),
+            None => self.config.radius_km,
+        }
+    }
+
+    // whether an entry at `cached`, `dist` km from `location`, may serve it. Both
+    // points must be within the radius of the other.
+    fn within_radius(&self, location: &Location, cached: &Location, dist: f64) -> bool {
+        dist < self.config.radius_km
+            && dist < self.radius_at(location)
+            && dist < self.radius_at(cached)
+    }
+
+    /// Finds the entry that would serve `location`, fresh or not: the nearest
+    /// cached point within the radius whose value `matches`, e.g. one in the
+    /// requested units. Returns its id and distance in km.
+    pub(crate) fn find(
+        &self,
+        location: &Location,
+        matches: impl Fn(&T) -> bool,
+    ) -> Option<(usize, f64)> {
+        self.store
+            .within(location, self.config.radius_km)
+            .into_iter()
+            .find(|&(id, dist)| {
+                self.store
+                    .read(id, |entry| {
+                        self.within_radius(location, &entry.location, dist) && matches(&entry.value)
+                    })
+                    .unwrap_or(false)
+            })
+    }
+
+    /// Looks up the value serving `location`, counting a hit if it is fresh.
+    /// The distance to the entry is recorded on the current span.
+    pub fn get(&self, location: &Location, matches: impl Fn(&T) -> bool) -> Lookup<T> {
+        let Some((id, dist)) = self.find(location, matches) else {
+            return Lookup::Miss;
+        };
+        trace!("Distance from given point {}km", dist);
+        Span::current().record("cache.distance_km", dist);
+        // the entry may have been replaced since it was found.
+        self.store
+            .read(id, |entry| {
+                if entry.expiry > Utc::now() {
+                    entry.hits.add();
+                    Lookup::Fresh(entry.value.clone())
+                } else {
+                    Lookup::Stale {
+                        id,
+                        value: entry.value.clone(),
+                    }
+                }
+            })
+            .unwrap_or(Lookup::Miss)
+    }
+
+    /// Up to `count` fresh entries within `radius_km` of `location` that
+    /// `matches`, nearest first, with their distance in km.
+    pub(crate) fn fresh_neighbors(
+        &self,
+        location: &Location,
+        radius_km: f64,
+        count: usize,
+        matches: impl Fn(&T) -> bool,
+    ) -> Vec<(f64, T)> {
+        let now = Utc::now();
+        self.store
+            .within(location, radius_km)
+            .into_iter()
+            .filter_map(|(id, dist)| {
+                self.store
+                    .read(id, |entry| {
+                        (entry.expiry > now && matches(&entry.value))
+                            .then(|| (dist, entry.value.clone()))
+                    })
+                    .flatten()
+            })
+            .take(count)
+            .collect()
+    }
+
+    /// Serves `location` from the cache, calling `fetch` when there is no fresh
+    /// entry. While OWM is unavailable a stale entry is served instead. Records
+    /// the outcome in metrics and on the current span.
+    pub(crate) async fn get_or_fetch<Fut>(
+        &self,
+        location: &Location,
+        matches: impl Fn(&T) -> bool,
+        metrics: &Metrics,
+        fetch: impl FnOnce() -> Fut,
+    ) -> anyhow::Result<(T, CacheStatus)>
+    where
+        Fut: Future<Output = anyhow::Result<Fetched<T>>>,
+    {
+        let stale = match self.get(location, matches) {
+            Lookup::Fresh(value) => return Ok(self.served(value, CacheStatus::Hit, metrics)),
+            Lookup::Stale { id, value } => Some((id, value)),
+            Lookup::Miss => None,
+        };
+        match fetch().await {
+            Ok(Fetched::Cache(value, ttl)) => {
+                // the fresh entry replaces the stale one.
+                let replaces = stale.map(|(id, _)| id);
+                self.insert(*location, value.clone(), ttl, replaces);
+                Ok(self.served(value, CacheStatus::Miss, metrics))
+            }
+            Ok(Fetched::Uncached(value)) => Ok(self.served(value, CacheStatus::Miss, metrics)),
+            // OWM is not being called or failing right now, serve stale data if we have any.
+            Err(err) if upstream::is_unavailable(&err) => match stale {
+                Some((id, value)) => {
+                    warn!("{}, serving stale {} data", err, self.name);
+                    self.store.read(id, |entry| entry.hits.add());
+                    Ok(self.served(value, CacheStatus::Stale, metrics))
+                }
+                None => Err(err),
+            },
+            Err(err) => Err(err),
+        }
+    }
+
+    fn served(&self, value: T, status: CacheStatus, metrics: &Metrics) -> (T, CacheStatus) {
+        metrics.record_cache(self.name, status);
+        Span::current().record("cache.status", status.as_str());
+        (value, status)
+    }
+
+    // the entry holding the same kind of value as `value` at exactly
+    // `location`, which a new value for it replaces.
+    fn same_entry(&self, location: &Location, value: &T) -> Option<usize> {
+        let key_part = value.key_part();
+        self.store
+            .within(location, SAME_POINT_KM)
+            .into_iter()
+            .map(|(id, _)| id)
+            .find(|&id| {
+                self.store
+                    .read(id, |entry| {
+                        entry.location == *location && entry.value.key_part() == key_part
+                    })
+                    .unwrap_or(false)
+            })
+    }
+
+    /// Caches `value` for `location`, removing the entry `replaces` first, or
+    /// else any entry with the same key at the same point. Returns the id of
+    /// the new entry.
+    pub fn insert(
+        &self,
+        location: Location,
+        value: T,
+        ttl: Duration,
+        replaces: Option<usize>,
+    ) -> usize {
+        let now = Utc::now();
+        let entry = Cached {
+            value,
+            location,
+            fetched: now,
+            expiry: now + ttl,
+            hits: Hits::default(),
+        };
+        let replaces = replaces.or_else(|| self.same_entry(&location, &entry.value));
+        self.store.insert(entry, replaces)
+    }
+
+    pub(crate) fn entry(&self, id: usize) -> Option<Cached<T>> {
+        self.store.read(id, |entry| entry.clone())
+    }
+
+    /// Every entry, expired ones included, ordered by id.
+    pub(crate) fn entries(&self) -> Vec<(usize, Cached<T>)> {
+        let mut all = self.store.entries();
+        all.sort_by_key(|(id, _)| *id);
+        all
+    }
+
+    /// Adds entries, e.g. from a snapshot, keeping their expiry and hits. An
+    /// entry with the same key at the same point is replaced.
+    pub(crate) fn restore(&self, restored: Vec<Cached<T>>) -> usize {
+        let count = restored.len();
+        for entry in restored {
+            let replaces = self.same_entry(&entry.location, &entry.value);
+            self.store.insert(entry, replaces);
+        }
+        count
+    }
+
+    /// A key for `value` cached at `location`, the same on every instance, so
+    /// it can be shared with an external cache: e.g. `weather:metric:9q8yy`.
+    pub(crate) fn key(&self, location: &Location, value: &T) -> String {
+        let cell = geohash(location, self.config.geohash_precision);
+        match value.key_part() {
+            Some(part) => format!("{}:{}:{}", self.name, part, cell),
+            None => format!("{}:{}", self.name, cell),
+        }
+    }
+
+    /// The admin API view of an entry.
+    pub(crate) fn summarize(&self, id: usize, entry: &Cached<T>) -> EntrySummary {
+        let now = Utc::now();
+        EntrySummary {
+            id,
+            key: self.key(&entry.location, &entry.value),
+            latitude: entry.location.latitude,
+            longitude: entry.location.longitude,
+            description: entry.value.describe(),
+            age_secs: (now - entry.fetched).num_seconds(),
+            expires_in_secs: (entry.expiry - now).num_seconds(),
+            hits: entry.hits.get(),
+        }
+    }
+}
+
+/// A cache entry as shown by the admin API.
+#[derive(Debug, Serialize)]
+pub(crate) struct EntrySummary {
+    pub(crate) id: usize,
+    pub(crate) key: String,
+    pub(crate) latitude: f64,
+    pub(crate) longitude: f64,
+    pub(crate) description: String,
+    pub(crate) age_secs: i64,
+    // negative once the entry has expired.
+    pub(crate) expires_in_secs: i64,
+    pub(crate) hits: u64,
+}
+
+/// Operations shared by every tier, whatever it caches.
+pub(crate) trait Tier {
+    fn name(&self) -> &'static str;
+    fn len(&self) -> usize;
+    fn summaries(&self) -> Vec<EntrySummary>;
+    fn summary(&self, id: usize) -> Option<EntrySummary>;
+    /// Removes the entries for which `predicate` holds and returns how many.
+    fn remove_where(&self, predicate: &dyn Fn(usize, &Location) -> bool) -> usize;
+    /// Removes the entries that expired longer than the stale grace ago and
+    /// returns how many.
+    fn evict_expired(&self) -> usize;
+    /// Whether the tier can still be locked, i.e. no request panicked holding it.
+    fn is_reachable(&self) -> bool;
+}
+
+impl<T: CacheValue> Tier for SpatialCache<T> {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn len(&self) -> usize {
+        self.store.len()
+    }
+
+    fn summaries(&self) -> Vec<EntrySummary> {
+        self.entries()
+            .iter()
+            .map(|(id, entry)| self.summarize(*id, entry))
+            .collect()
+    }
+
+    fn summary(&self, id: usize) -> Option<EntrySummary> {
+        self.entry(id).map(|entry| self.summarize(id, &entry))
+    }
+
+    fn remove_where(&self, predicate: &dyn Fn(usize, &Location) -> bool) -> usize {
+        self.store
+            .remove_where(&|id, entry| predicate(id, &entry.location))
+    }
+
+    fn evict_expired(&self) -> usize {
+        let cutoff = Utc::now() - self.config.stale_grace;
+        self.store.remove_where(&|_, entry| entry.expiry < cutoff)
+    }
+
+    fn is_reachable(&self) -> bool {
+        self.store.is_reachable()
+    }
+}
+
+/// The cache tiers, one per type of upstream data, since each changes at a
+/// different pace: a place name never changes and AQI updates hourly.
+#[derive(Debug)]
+pub(crate) struct Caches {
+    pub(crate) weather: SpatialCache<CachedForecast>,
+    pub(crate) aqi: SpatialCache<i64>,
+    pub(crate) reverse_geocode: SpatialCache<ReverseGeocode>,
+}
+
+impl Caches {
+    /// Creates empty tiers, the ones listed in `cache.adaptive_radius.tiers`
+    /// using `radius_model`.
+    pub(crate) fn new(config: &CacheConfig, radius_model: Option<RadiusModel>) -> Self {
+        let radius_model = radius_model.map(Arc::new);
+        let model_for = |tier: &str| {
+            radius_model
+                .clone()
+                .filter(|_| config.adaptive_radius.tiers.iter().any(|name| name == tier))
+        };
+        Self {
+            weather: SpatialCache::new("weather", config.weather_tier(), model_for("weather")),
+            aqi: SpatialCache::new("aqi", config.aqi_tier(), model_for("aqi")),
+            reverse_geocode: SpatialCache::new(
+                "reverse_geocode",
+                config.reverse_geocode_tier(),
+                model_for("reverse_geocode"),
+            ),
+        }
+    }
+
+    pub(crate) fn tiers(&self) -> [&dyn Tier; 3] {
+        [&self.weather, &self.aqi, &self.reverse_geocode]
+    }
+
+    pub(crate) fn tier(&self, name: &str) -> Option<&dyn Tier> {
+        self.tiers().into_iter().find(|tier| tier.name() == name)
+    }
+}
+
+/// Evicts the entries of every tier that are past their stale grace every
+/// `interval`, so the cache does not keep every point ever queried.
+pub(crate) async fn evict_expired(caches: &Caches, interval: std::time::Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        for tier in caches.tiers() {
+            let evicted = tier.evict_expired();
+            if evicted > 0 {
+                debug!("Evicted {} expired {} entries", evicted, tier.name());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+impl CacheValue for u32 {
+    fn describe(&self) -> String {
+        self.to_string()
+    }
+}
+
+#[test]
+fn test_lookup_respects_radius_and_expiry() {
+    for index in [CacheIndex::KdTree, CacheIndex::Geohash] {
+        check_lookup(index);
+    }
+}
+
+#[cfg(test)]
+fn tier_config(index: CacheIndex) -> TierConfig {
+    TierConfig {
+        radius_km: 10.0,
+        ttl: Duration::minutes(15),
+        stale_grace: Duration::hours(1),
+        index,
+        geohash_precision: 5,
+        shards: 4,
+    }
+}
+
+#[cfg(test)]
+fn check_lookup(index: CacheIndex) {
+    let cache = SpatialCache::<u32>::new("test", tier_config(index), None);
+    assert!(matches!(
+        cache.get(&Location::at(37.5, -122.0), |_| true),
+        Lookup::Miss
+    ));
+
+    let fresh = cache.insert(Location::at(37.5, -122.0), 1, Duration::minutes(15), None);
+    assert!(matches!(
+        cache.get(&Location::at(37.55, -122.0), |_| true),
+        Lookup::Fresh(1)
+    ));
+    // about 55km away.
+    assert!(matches!(
+        cache.get(&Location::at(38.0, -122.0), |_| true),
+        Lookup::Miss
+    ));
+    assert!(matches!(
+        cache.get(&Location::at(37.5, -122.0), |value| *value == 2),
+        Lookup::Miss
+    ));
+
+    let expired = cache.insert(Location::at(40.0, -100.0), 2, Duration::minutes(-1), None);
+    assert!(matches!(
+        cache.get(&Location::at(40.0, -100.0), |_| true),
+        Lookup::Stale { id, value: 2 } if id == expired
+    ));
+    cache.insert(
+        Location::at(40.0, -100.0),
+        3,
+        Duration::minutes(15),
+        Some(expired),
+    );
+    assert!(matches!(
+        cache.get(&Location::at(40.0, -100.0), |_| true),
+        Lookup::Fresh(3)
+    ));
+
+    assert_eq!(cache.len(), 2);
+    let summary = cache.summary(fresh).unwrap();
+    assert_eq!(summary.hits, 1);
+    assert_eq!(summary.key, "test:9q9jr");
+    assert_eq!(
+        cache.remove_where(&|_, location| location.latitude > 39.0),
+        1
+    );
+    assert_eq!(cache.len(), 1);
+
+    // restoring the same entries again, e.g. a snapshot imported twice,
+    // replaces them.
+    let entries = cache.entries().into_iter().map(|(_, entry)| entry);
+    assert_eq!(cache.restore(entries.collect()), 1);
+    assert_eq!(cache.len(), 1);
+}
+
+#[test]
+fn test_evicts_entries_past_the_stale_grace() {
+    for index in [CacheIndex::KdTree, CacheIndex::Geohash] {
+        let cache = SpatialCache::<u32>::new("test", tier_config(index), None);
+        cache.insert(Location::at(37.5, -122.0), 1, Duration::minutes(15), None);
+        // still served while OWM is unavailable.
+        cache.insert(Location::at(40.0, -100.0), 2, Duration::minutes(-30), None);
+        cache.insert(Location::at(45.0, -90.0), 3, Duration::minutes(-90), None);
+
+        assert_eq!(cache.evict_expired(), 1);
+        assert_eq!(cache.len(), 2);
+        assert!(matches!(
+            cache.get(&Location::at(40.0, -100.0), |_| true),
+            Lookup::Stale { value: 2, .. }
+        ));
+        assert!(matches!(
+            cache.get(&Location::at(45.0, -90.0), |_| true),
+            Lookup::Miss
+        ));
+    }
+}
+
+#[test]
+fn test_units_alternating_at_one_location() {
+    use crate::weather::entities::Units;
+
+    for index in [CacheIndex::KdTree, CacheIndex::Geohash] {
+        let cache = SpatialCache::<CachedForecast>::new("weather", tier_config(index), None);
+        let at = Location::at(37.5, -122.0);
+        // an imperial entry next door must not hide the metric one here.
+        let nearby = Location::at(37.501, -122.0);
+        let forecast = |units| CachedForecast {
+            weather: Default::default(),
+            units,
+            daily_only: false,
+        };
+        cache.insert(
+            nearby,
+            forecast(Units::Imperial),
+            Duration::minutes(15),
+            None,
+        );
+        for round in 0..50 {
+            for units in [Units::Metric, Units::Imperial] {
+                match cache.get(&at, |cached| cached.units == units) {
+                    Lookup::Fresh(cached) => assert_eq!(cached.units, units),
+                    _ => {
+                        assert_eq!((round, units), (0, Units::Metric));
+                        cache.insert(at, forecast(units), Duration::minutes(15), None);
+                    }
+                }
+                // refetching replaces the entry for these units.
+                cache.insert(at, forecast(units), Duration::minutes(15), None);
+            }
+        }
+        assert_eq!(cache.len(), 3);
+    }
+}
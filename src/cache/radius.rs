@@ -0,0 +1,233 @@
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context};
+use kiddo::{distance::squared_euclidean, float::kdtree::KdTree};
+use serde::Deserialize;
+
+use crate::{
+    entities::Location,
+    validation::normalize_location,
+    weather::utils::{jitter, squared_chord_to_km, unit_sphere_point},
+};
+
+use super::CacheTree;
+
+/// Shrinks the cache radius where weather changes over short distances:
+/// mountains, coastlines and dense cities. Each rule scales the radius of a
+/// tier by its factor, the smallest applicable factor wins.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct AdaptiveRadiusConfig {
+    // CSV with one `latitude,longitude,relief_m,coast_km,population_per_km2`
+    // sample per line. The radius is fixed when not set.
+    pub(crate) terrain_file: Option<String>,
+    // cache tiers whose radius adapts.
+    pub(crate) tiers: Vec<String>,
+    // queries further than this from every sample keep the configured radius.
+    pub(crate) max_sample_distance_km: f64,
+    // elevation range around a sample above which it counts as mountainous.
+    pub(crate) mountain_relief_m: f64,
+    pub(crate) mountain_factor: f64,
+    pub(crate) coast_within_km: f64,
+    pub(crate) coastal_factor: f64,
+    pub(crate) dense_population_per_km2: f64,
+    pub(crate) dense_factor: f64,
+    // the radius never shrinks below this.
+    pub(crate) min_radius_km: f64,
+}
+
+impl Default for AdaptiveRadiusConfig {
+    fn default() -> Self {
+        Self {
+            terrain_file: None,
+            tiers: vec![String::from("weather"), String::from("aqi")],
+            max_sample_distance_km: 25.0,
+            mountain_relief_m: 500.0,
+            mountain_factor: 0.3,
+            coast_within_km: 10.0,
+            coastal_factor: 0.5,
+            dense_population_per_km2: 2000.0,
+            dense_factor: 0.5,
+            min_radius_km: 1.0,
+        }
+    }
+}
+
+/// Terrain and population around a point of the terrain dataset.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct TerrainSample {
+    pub(crate) location: Location,
+    // difference between the highest and lowest elevation nearby.
+    pub(crate) relief_m: f64,
+    // distance to the nearest coastline.
+    pub(crate) coast_km: f64,
+    pub(crate) population_per_km2: f64,
+}
+
+/// Looks up the terrain sample nearest to a location to scale the cache radius.
+#[derive(Debug)]
+pub struct RadiusModel {
+    config: AdaptiveRadiusConfig,
+    kdtree: CacheTree,
+    samples: Vec<TerrainSample>,
+}
+
+impl RadiusModel {
+    pub(crate) fn new(config: AdaptiveRadiusConfig, samples: Vec<TerrainSample>) -> Self {
+        let mut kdtree: CacheTree = KdTree::new();
+        for (idx, sample) in samples.iter().enumerate() {
+            // terrain data is usually gridded, so many samples share a latitude.
+            let point = unit_sphere_point(sample.location.latitude, sample.location.longitude);
+            kdtree.add(&jitter(point, idx), idx);
+        }
+        Self {
+            config,
+            kdtree,
+            samples,
+        }
+    }
+
+    /// The model described by `config`, or None if it has no terrain file.
+    pub(crate) fn load(config: &AdaptiveRadiusConfig) -> anyhow::Result<Option<Self>> {
+        let Some(path) = &config.terrain_file else {
+            return Ok(None);
+        };
+        let samples = read_terrain_file(Path::new(path))?;
+        Ok(Some(Self::new(config.clone(), samples)))
+    }
+
+    /// `base_km` scaled for the terrain at `location`.
+    pub(crate) fn radius_km(&self, base_km: f64, location: &Location) -> f64 {
+        let factor = self.factor(location);
+        if factor >= 1.0 {
+            return base_km;
+        }
+        (base_km * factor)
+            .max(self.config.min_radius_km)
+            .min(base_km)
+    }
+
+    // the smallest factor of the rules that apply at `location`, 1 if none does.
+    fn factor(&self, location: &Location) -> f64 {
+        if self.samples.is_empty() {
+            return 1.0;
+        }
+        let (squared_chord, idx) = self.kdtree.nearest_one(
+            &unit_sphere_point(location.latitude, location.longitude),
+            &squared_euclidean,
+        );
+        if squared_chord_to_km(squared_chord) > self.config.max_sample_distance_km {
+            return 1.0;
+        }
+        let sample = &self.samples[idx];
+        let config = &self.config;
+        [
+            (
+                sample.relief_m >= config.mountain_relief_m,
+                config.mountain_factor,
+            ),
+            (
+                sample.coast_km <= config.coast_within_km,
+                config.coastal_factor,
+            ),
+            (
+                sample.population_per_km2 >= config.dense_population_per_km2,
+                config.dense_factor,
+            ),
+        ]
+        .iter()
+        .filter(|(applies, _)| *applies)
+        .map(|(_, factor)| *factor)
+        .fold(1.0, f64::min)
+    }
+}
+
+/// Reads a terrain file with one
+/// `latitude,longitude,relief_m,coast_km,population_per_km2` line per sample.
+/// Blank lines and lines starting with `#` are skipped.
+pub(crate) fn read_terrain_file(path: &Path) -> anyhow::Result<Vec<TerrainSample>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("could not read {}", path.display()))?;
+    parse_terrain(&contents).with_context(|| format!("invalid samples in {}", path.display()))
+}
+
+fn parse_terrain(contents: &str) -> anyhow::Result<Vec<TerrainSample>> {
+    let mut samples = vec![];
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields = line
+            .split(',')
+            .map(|field| {
+                field.trim().parse::<f64>().with_context(|| {
+                    format!("line {}: invalid number {:?}", number + 1, field.trim())
+                })
+            })
+            .collect::<anyhow::Result<Vec<f64>>>()?;
+        let [latitude, longitude, relief_m, coast_km, population_per_km2] = fields[..] else {
+            bail!(
+                "line {}: expected latitude,longitude,relief_m,coast_km,population_per_km2",
+                number + 1
+            );
+        };
+        samples.push(TerrainSample {
+            location: normalize_location(latitude, longitude)
+                .map_err(|err| anyhow!("line {}: {}", number + 1, err))?,
+            relief_m,
+            coast_km,
+            population_per_km2,
+        });
+    }
+    Ok(samples)
+}
+
+#[test]
+fn test_radius_shrinks_with_terrain() {
+    let samples = parse_terrain(
+        "# lat,lon,relief_m,coast_km,population_per_km2\n\
+         39.6, -106.0, 1200, 900, 20\n\
+         37.77, -122.42, 150, 2, 7000\n\
+         41.0, -100.0, 40, 1000, 10\n",
+    )
+    .unwrap();
+    let model = RadiusModel::new(AdaptiveRadiusConfig::default(), samples);
+
+    // Rockies: mountain factor.
+    assert!((model.radius_km(10.0, &Location::at(39.62, -106.02)) - 3.0).abs() < 1e-9);
+    // San Francisco is coastal and dense, both halve.
+    assert!((model.radius_km(10.0, &Location::at(37.78, -122.41)) - 5.0).abs() < 1e-9);
+    // the geocode tier is already tight, it stops at min_radius_km.
+    assert!((model.radius_km(1.0, &Location::at(37.78, -122.41)) - 1.0).abs() < 1e-9);
+    // plains and places without a nearby sample keep the configured radius.
+    assert_eq!(model.radius_km(10.0, &Location::at(41.0, -100.0)), 10.0);
+    assert_eq!(model.radius_km(10.0, &Location::at(0.0, 0.0)), 10.0);
+
+    assert!(parse_terrain("39.6,-106.0,1200").is_err());
+    assert!(parse_terrain("39.6,-106.0,high,900,20").is_err());
+    assert!(parse_terrain("96.0,-106.0,1200,900,20").is_err());
+    assert!(parse_terrain("NaN,-106.0,1200,900,20").is_err());
+}
+
+#[test]
+fn test_gridded_terrain() {
+    // a 100x100 grid at 0.01 degree steps, the mountainous half in the west.
+    let mut samples = vec![];
+    for row in 0..100 {
+        for column in 0..100 {
+            samples.push(TerrainSample {
+                location: Location::at(
+                    39.0 + f64::from(row) * 0.01,
+                    -106.0 + f64::from(column) * 0.01,
+                ),
+                relief_m: if column < 50 { 1200.0 } else { 40.0 },
+                coast_km: 900.0,
+                population_per_km2: 20.0,
+            });
+        }
+    }
+    let model = RadiusModel::new(AdaptiveRadiusConfig::default(), samples);
+    assert!((model.radius_km(10.0, &Location::at(39.5, -105.9)) - 3.0).abs() < 1e-9);
+    assert_eq!(model.radius_km(10.0, &Location::at(39.5, -105.1)), 10.0);
+}
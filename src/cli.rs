@@ -0,0 +1,107 @@
+use std::path::PathBuf;
+
+use anyhow::{bail, Context};
+
+use crate::auth::middleware::ADMIN_KEY_HEADER;
+
+pub(crate) const USAGE: &str = "usage:
+  rust-weather-api                                      run the server
+  rust-weather-api snapshot export <server-url> <file>  save the cache of a running server
+  rust-weather-api snapshot import <server-url> <file>  load a snapshot into a running server
+
+snapshot commands send the admin key from the WEATHER_ADMIN_KEY env var.";
+
+// admin key used by the snapshot subcommands.
+const ADMIN_KEY_ENV: &str = "WEATHER_ADMIN_KEY";
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Command {
+    Serve,
+    ExportSnapshot { server: String, path: PathBuf },
+    ImportSnapshot { server: String, path: PathBuf },
+}
+
+pub(crate) fn parse(args: &[String]) -> anyhow::Result<Command> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        [] => Ok(Command::Serve),
+        ["snapshot", action, server, path] => {
+            let server = server.trim_end_matches('/').to_string();
+            let path = PathBuf::from(path);
+            match *action {
+                "export" => Ok(Command::ExportSnapshot { server, path }),
+                "import" => Ok(Command::ImportSnapshot { server, path }),
+                _ => bail!("unknown snapshot action {:?}\n{}", action, USAGE),
+            }
+        }
+        _ => bail!("unexpected arguments\n{}", USAGE),
+    }
+}
+
+/// Runs a snapshot subcommand against the admin API of a running server.
+pub(crate) async fn run(command: Command) -> anyhow::Result<()> {
+    let mut headers = reqwest::header::HeaderMap::new();
+    if let Ok(key) = std::env::var(ADMIN_KEY_ENV) {
+        headers.insert(ADMIN_KEY_HEADER, key.parse()?);
+    }
+    let client = reqwest::Client::builder()
+        .default_headers(headers)
+        .build()?;
+    match command {
+        Command::Serve => unreachable!("the server is not a subcommand"),
+        Command::ExportSnapshot { server, path } => {
+            let bytes = client
+                .get(format!("{}/admin/cache/snapshot", server))
+                .send()
+                .await?
+                .error_for_status()?
+                .bytes()
+                .await?;
+            std::fs::write(&path, &bytes)
+                .with_context(|| format!("could not write {}", path.display()))?;
+            println!("Wrote {} bytes to {}", bytes.len(), path.display());
+        }
+        Command::ImportSnapshot { server, path } => {
+            let bytes = std::fs::read(&path)
+                .with_context(|| format!("could not read {}", path.display()))?;
+            let response = client
+                .post(format!("{}/admin/cache/snapshot", server))
+                .header(reqwest::header::CONTENT_TYPE, "application/x-protobuf")
+                .body(bytes)
+                .send()
+                .await?
+                .error_for_status()?
+                .text()
+                .await?;
+            println!("{}", response);
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_parse_commands() {
+    let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+    assert_eq!(parse(&args(&[])).unwrap(), Command::Serve);
+    assert_eq!(
+        parse(&args(&[
+            "snapshot",
+            "export",
+            "http://localhost:8080/",
+            "cache.bin"
+        ]))
+        .unwrap(),
+        Command::ExportSnapshot {
+            server: String::from("http://localhost:8080"),
+            path: PathBuf::from("cache.bin"),
+        }
+    );
+    assert!(parse(&args(&[
+        "snapshot",
+        "copy",
+        "http://localhost:8080",
+        "cache.bin"
+    ]))
+    .is_err());
+    assert!(parse(&args(&["serve", "now"])).is_err());
+}
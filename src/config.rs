@@ -0,0 +1,706 @@
+use std::{collections::HashMap, env, path::Path, time::Duration};
+
+use anyhow::{anyhow, bail, Context};
+use serde::Deserialize;
+use toml::{Table, Value};
+
+use crate::auth::{ratelimit::BucketConfig, ClientConfig, API_ENDPOINTS};
+use crate::cache::{
+    radius::{read_terrain_file, AdaptiveRadiusConfig},
+    CacheIndex, TierConfig, TIER_NAMES,
+};
+use crate::redact::MIN_SECRET_LEN;
+use crate::telemetry::{LoggingConfig, TelemetryConfig};
+use crate::upstream::{
+    breaker::BreakerConfig,
+    budget::{BudgetConfig, BudgetLimits},
+    client::RetryPolicy,
+    keys::{read_keys_file, KeyStrategy},
+    Endpoint,
+};
+use crate::weather::{
+    interpolation::InterpolationConfig, ttl::TtlPolicy, warming::read_locations_file,
+};
+
+// config file read when WEATHER_CONFIG is not set; it is optional.
+const DEFAULT_CONFIG_PATH: &str = "config.toml";
+// env vars named WEATHER__<SECTION>__<FIELD> override values from the file.
+const ENV_PREFIX: &str = "WEATHER__";
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    pub(crate) server: ServerConfig,
+    pub(crate) owm: OwmConfig,
+    pub(crate) cache: CacheConfig,
+    pub(crate) warming: WarmingConfig,
+    pub(crate) http: HttpConfig,
+    pub(crate) breaker: BreakerSettings,
+    pub(crate) budget: HashMap<Endpoint, BudgetLimits>,
+    pub(crate) auth: AuthConfig,
+    pub(crate) rate_limit: RateLimitConfig,
+    pub(crate) logging: LoggingConfig,
+    pub(crate) telemetry: TelemetryConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ServerConfig {
+    pub(crate) host: String,
+    pub(crate) port: u16,
+    // how long in-flight requests may take to finish once shutdown starts.
+    pub(crate) shutdown_timeout_secs: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            host: String::from("0.0.0.0"),
+            port: 8080,
+            shutdown_timeout_secs: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct OwmConfig {
+    pub(crate) api_key: String,
+    // additional keys shared between calls according to key_strategy.
+    pub(crate) api_keys: Vec<String>,
+    // file with one key per line, reloaded when it changes.
+    pub(crate) keys_file: Option<String>,
+    pub(crate) keys_reload_secs: u64,
+    pub(crate) key_strategy: KeyStrategy,
+    // how long a key that hit its quota is set aside if OWM sends no Retry-After.
+    pub(crate) key_cooldown_secs: u64,
+    pub(crate) base_url: String,
+}
+
+impl Default for OwmConfig {
+    fn default() -> Self {
+        Self {
+            api_key: String::new(),
+            api_keys: vec![],
+            keys_file: None,
+            keys_reload_secs: 30,
+            key_strategy: KeyStrategy::default(),
+            key_cooldown_secs: 60,
+            base_url: String::from("http://api.openweathermap.org"),
+        }
+    }
+}
+
+impl OwmConfig {
+    /// Every configured key, including the ones in `keys_file`.
+    pub(crate) fn all_keys(&self) -> anyhow::Result<Vec<String>> {
+        let mut keys = self.static_keys();
+        if let Some(path) = &self.keys_file {
+            keys.extend(read_keys_file(Path::new(path))?);
+        }
+        keys.retain(|key| !key.trim().is_empty());
+        Ok(keys)
+    }
+
+    /// The keys set in the config itself, which a `keys_file` reload keeps.
+    pub(crate) fn static_keys(&self) -> Vec<String> {
+        let mut keys = vec![self.api_key.clone()];
+        keys.extend(self.api_keys.iter().cloned());
+        keys.retain(|key| !key.trim().is_empty());
+        keys
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct CacheConfig {
+    // cached weather within this distance of a query is reused.
+    pub(crate) radius_km: f64,
+    // TTL for weather entries no adaptive_ttl rule applies to.
+    pub(crate) ttl_minutes: i64,
+    pub(crate) adaptive_ttl: TtlPolicy,
+    // air quality varies over larger areas and is updated hourly.
+    pub(crate) aqi_radius_km: f64,
+    pub(crate) aqi_ttl_minutes: i64,
+    // place names need a tight radius but practically never change.
+    pub(crate) reverse_geocode_radius_km: f64,
+    pub(crate) reverse_geocode_ttl_minutes: i64,
+    // expired entries are served while OWM is unavailable for this long after
+    // they expire, and evicted by a sweep every eviction_interval_secs after that.
+    pub(crate) stale_grace_minutes: i64,
+    pub(crate) eviction_interval_secs: u64,
+    pub(crate) adaptive_radius: AdaptiveRadiusConfig,
+    pub(crate) interpolation: InterpolationConfig,
+    pub(crate) index: CacheIndex,
+    // geohash length, 5 gives cells of about 5x5km. Also used in entry keys.
+    pub(crate) geohash_precision: usize,
+    // independently locked parts of each tier with the geohash index.
+    pub(crate) shards: usize,
+    // the cache is written here on shutdown.
+    pub(crate) snapshot_path: Option<String>,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            radius_km: 10.0,
+            ttl_minutes: 15,
+            adaptive_ttl: TtlPolicy::default(),
+            aqi_radius_km: 20.0,
+            aqi_ttl_minutes: 60,
+            reverse_geocode_radius_km: 1.0,
+            reverse_geocode_ttl_minutes: 14 * 24 * 60,
+            stale_grace_minutes: 24 * 60,
+            eviction_interval_secs: 300,
+            adaptive_radius: AdaptiveRadiusConfig::default(),
+            interpolation: InterpolationConfig::default(),
+            index: CacheIndex::default(),
+            geohash_precision: 5,
+            shards: 16,
+            snapshot_path: None,
+        }
+    }
+}
+
+impl CacheConfig {
+    pub(crate) fn weather_tier(&self) -> TierConfig {
+        self.tier_config(self.radius_km, self.ttl_minutes)
+    }
+
+    pub(crate) fn aqi_tier(&self) -> TierConfig {
+        self.tier_config(self.aqi_radius_km, self.aqi_ttl_minutes)
+    }
+
+    pub(crate) fn reverse_geocode_tier(&self) -> TierConfig {
+        self.tier_config(
+            self.reverse_geocode_radius_km,
+            self.reverse_geocode_ttl_minutes,
+        )
+    }
+
+    fn tier_config(&self, radius_km: f64, ttl_minutes: i64) -> TierConfig {
+        TierConfig {
+            radius_km,
+            ttl: chrono::Duration::minutes(ttl_minutes),
+            stale_grace: chrono::Duration::minutes(self.stale_grace_minutes),
+            index: self.index,
+            geohash_precision: self.geohash_precision,
+            shards: self.shards,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct WarmingConfig {
+    // file with one `latitude,longitude,units[,priority]` line per location
+    // to keep fresh in the cache.
+    pub(crate) locations_file: Option<String>,
+    // entries are refreshed this long before they expire.
+    pub(crate) refresh_before_secs: u64,
+    pub(crate) check_interval_secs: u64,
+    // refreshes across all locations; each makes one onecall call. AQI and
+    // place names are not warmed, their tiers outlive a forecast.
+    pub(crate) max_refreshes_per_minute: u32,
+}
+
+impl Default for WarmingConfig {
+    fn default() -> Self {
+        Self {
+            locations_file: None,
+            refresh_before_secs: 60,
+            check_interval_secs: 15,
+            max_refreshes_per_minute: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct HttpConfig {
+    pub(crate) connect_timeout_ms: u64,
+    pub(crate) request_timeout_ms: u64,
+    pub(crate) max_retries: u32,
+    pub(crate) retry_base_delay_ms: u64,
+    pub(crate) retry_max_delay_ms: u64,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        let retry = RetryPolicy::default();
+        Self {
+            connect_timeout_ms: 5_000,
+            request_timeout_ms: 10_000,
+            max_retries: retry.max_retries,
+            retry_base_delay_ms: retry.base_delay.as_millis() as u64,
+            retry_max_delay_ms: retry.max_delay.as_millis() as u64,
+        }
+    }
+}
+
+impl HttpConfig {
+    pub(crate) fn connect_timeout(&self) -> Duration {
+        Duration::from_millis(self.connect_timeout_ms)
+    }
+
+    pub(crate) fn request_timeout(&self) -> Duration {
+        Duration::from_millis(self.request_timeout_ms)
+    }
+
+    pub(crate) fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_retries: self.max_retries,
+            base_delay: Duration::from_millis(self.retry_base_delay_ms),
+            max_delay: Duration::from_millis(self.retry_max_delay_ms),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct BreakerSettings {
+    pub(crate) failure_threshold: u32,
+    pub(crate) open_duration_secs: u64,
+}
+
+impl Default for BreakerSettings {
+    fn default() -> Self {
+        let breaker = BreakerConfig::default();
+        Self {
+            failure_threshold: breaker.failure_threshold,
+            open_duration_secs: breaker.open_duration.as_secs(),
+        }
+    }
+}
+
+impl BreakerSettings {
+    pub(crate) fn breaker_config(&self) -> BreakerConfig {
+        BreakerConfig {
+            failure_threshold: self.failure_threshold,
+            open_duration: Duration::from_secs(self.open_duration_secs),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct AuthConfig {
+    // require a client API key on every /v1/api route.
+    pub(crate) enabled: bool,
+    pub(crate) clients: Vec<ClientConfig>,
+    // keys accepted on /admin routes in the X-Admin-Key header, whether or not
+    // client auth is enabled. With none the admin API rejects every request.
+    pub(crate) admin_keys: Vec<String>,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            clients: vec![],
+            admin_keys: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct RateLimitConfig {
+    pub(crate) enabled: bool,
+    pub(crate) per_key: BucketConfig,
+    pub(crate) per_ip: BucketConfig,
+    // use X-Forwarded-For / Forwarded for the client IP, only behind a trusted proxy.
+    pub(crate) trust_proxy_headers: bool,
+    // tokens a request costs when served from cache and when it went to OWM.
+    pub(crate) cache_hit_cost: f64,
+    pub(crate) cache_miss_cost: f64,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            per_key: BucketConfig {
+                capacity: 120.0,
+                refill_per_sec: 2.0,
+            },
+            per_ip: BucketConfig::default(),
+            trust_proxy_headers: false,
+            cache_hit_cost: 1.0,
+            cache_miss_cost: 1.0,
+        }
+    }
+}
+
+impl Config {
+    /// Loads the config file named by WEATHER_CONFIG (or `config.toml` if it
+    /// exists), applies env var overrides and validates the result.
+    pub(crate) fn load() -> anyhow::Result<Self> {
+        let (path, required) = match env::var("WEATHER_CONFIG") {
+            Ok(path) => (path, true),
+            Err(_) => (String::from(DEFAULT_CONFIG_PATH), false),
+        };
+        let mut table = if required || Path::new(&path).exists() {
+            let contents = std::fs::read_to_string(&path)
+                .with_context(|| format!("could not read config file {path}"))?;
+            contents
+                .parse::<Table>()
+                .with_context(|| format!("could not parse config file {path}"))?
+        } else {
+            Table::new()
+        };
+        apply_env_overrides(&mut table, env::vars())?;
+        let config = Config::deserialize(Value::Table(table)).context("invalid configuration")?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Checks the values that serde cannot, reporting every problem at once.
+    pub(crate) fn validate(&self) -> anyhow::Result<()> {
+        let mut problems = vec![];
+        match self.owm.all_keys() {
+            Ok(keys) if keys.is_empty() => problems.push(String::from(
+                "no OWM key is set (set owm.api_key, owm.api_keys or owm.keys_file, or OWM_KEY)",
+            )),
+            Ok(keys) if keys.iter().any(|key| is_short_secret(key)) => problems.push(format!(
+                "OWM keys must be at least {MIN_SECRET_LEN} characters long"
+            )),
+            Ok(_) => {}
+            Err(err) => problems.push(format!("owm.keys_file: {err:#}")),
+        }
+        let radius = &self.cache.adaptive_radius;
+        if let Some(path) = &radius.terrain_file {
+            if let Err(err) = read_terrain_file(Path::new(path)) {
+                problems.push(format!("cache.adaptive_radius.terrain_file: {err:#}"));
+            }
+        }
+        for tier in &radius.tiers {
+            if !TIER_NAMES.contains(&tier.as_str()) {
+                problems.push(format!(
+                    "cache.adaptive_radius.tiers: unknown tier {:?}, expected one of {:?}",
+                    tier, TIER_NAMES
+                ));
+            }
+        }
+        for (name, factor) in [
+            ("mountain_factor", radius.mountain_factor),
+            ("coastal_factor", radius.coastal_factor),
+            ("dense_factor", radius.dense_factor),
+        ] {
+            if !(factor > 0.0 && factor <= 1.0) {
+                problems.push(format!("cache.adaptive_radius.{} must be in (0, 1]", name));
+            }
+        }
+        if !(radius.min_radius_km > 0.0 && radius.max_sample_distance_km > 0.0) {
+            problems.push(String::from(
+                "cache.adaptive_radius.min_radius_km and max_sample_distance_km must be positive",
+            ));
+        }
+        let interpolation = &self.cache.interpolation;
+        if interpolation.neighbors < 2 {
+            problems.push(String::from(
+                "cache.interpolation.neighbors must be at least 2",
+            ));
+        }
+        if !(interpolation.radius_km.is_finite()
+            && interpolation.radius_km > 0.0
+            && interpolation.power > 0.0)
+        {
+            problems.push(String::from(
+                "cache.interpolation.radius_km and power must be positive",
+            ));
+        }
+        if let Some(path) = &self.warming.locations_file {
+            if let Err(err) = read_locations_file(Path::new(path)) {
+                problems.push(format!("warming.locations_file: {err:#}"));
+            }
+        }
+        if self.warming.check_interval_secs == 0 {
+            problems.push(String::from("warming.check_interval_secs must be positive"));
+        }
+        if self.warming.max_refreshes_per_minute == 0 {
+            problems.push(String::from(
+                "warming.max_refreshes_per_minute must be positive",
+            ));
+        }
+        if self.owm.keys_reload_secs == 0 {
+            problems.push(String::from("owm.keys_reload_secs must be positive"));
+        }
+        if !self.owm.base_url.starts_with("http://") && !self.owm.base_url.starts_with("https://") {
+            problems.push(format!(
+                "owm.base_url must be an http(s) URL, got {:?}",
+                self.owm.base_url
+            ));
+        }
+        if self.server.port == 0 {
+            problems.push(String::from("server.port must not be 0"));
+        }
+        let cache = &self.cache;
+        for (prefix, radius_km, ttl_minutes) in [
+            ("", cache.radius_km, cache.ttl_minutes),
+            ("aqi_", cache.aqi_radius_km, cache.aqi_ttl_minutes),
+            (
+                "reverse_geocode_",
+                cache.reverse_geocode_radius_km,
+                cache.reverse_geocode_ttl_minutes,
+            ),
+        ] {
+            if !(radius_km.is_finite() && radius_km > 0.0) {
+                problems.push(format!(
+                    "cache.{}radius_km must be a positive number",
+                    prefix
+                ));
+            }
+            if ttl_minutes <= 0 {
+                problems.push(format!("cache.{}ttl_minutes must be positive", prefix));
+            }
+        }
+        if !(1..=12).contains(&cache.geohash_precision) {
+            problems.push(String::from(
+                "cache.geohash_precision must be between 1 and 12",
+            ));
+        }
+        if cache.shards == 0 {
+            problems.push(String::from("cache.shards must be positive"));
+        }
+        if cache.stale_grace_minutes < 0 {
+            problems.push(String::from(
+                "cache.stale_grace_minutes must not be negative",
+            ));
+        }
+        if cache.eviction_interval_secs == 0 {
+            problems.push(String::from(
+                "cache.eviction_interval_secs must be positive",
+            ));
+        }
+        let ttl = &self.cache.adaptive_ttl;
+        if [
+            ttl.storm_minutes,
+            ttl.alert_minutes,
+            ttl.precipitation_change_minutes,
+            ttl.stable_clear_minutes,
+            ttl.daily_only_minutes,
+        ]
+        .iter()
+        .any(|minutes| *minutes <= 0)
+        {
+            problems.push(String::from(
+                "cache.adaptive_ttl durations must be positive",
+            ));
+        }
+        if self.http.connect_timeout_ms == 0 || self.http.request_timeout_ms == 0 {
+            problems.push(String::from("http timeouts must be positive"));
+        }
+        if self.http.retry_base_delay_ms > self.http.retry_max_delay_ms {
+            problems.push(String::from(
+                "http.retry_base_delay_ms must not exceed http.retry_max_delay_ms",
+            ));
+        }
+        if self.breaker.failure_threshold == 0 {
+            problems.push(String::from("breaker.failure_threshold must be positive"));
+        }
+        if self.auth.enabled && self.auth.clients.is_empty() {
+            problems.push(String::from(
+                "auth.enabled is true but no auth.clients are configured (set auth.clients or \
+                 WEATHER__AUTH__CLIENTS, or auth.enabled = false to serve without client keys)",
+            ));
+        }
+        let mut client_keys = std::collections::HashSet::new();
+        for client in &self.auth.clients {
+            if client.key.trim().is_empty() {
+                problems.push(format!("auth client {:?} has an empty key", client.name));
+            } else if is_short_secret(&client.key) {
+                problems.push(format!(
+                    "auth client {:?} has a key shorter than {MIN_SECRET_LEN} characters",
+                    client.name
+                ));
+            } else if !client_keys.insert(&client.key) {
+                problems.push(format!(
+                    "auth client {:?} reuses another client's key",
+                    client.name
+                ));
+            }
+            for endpoint in &client.allowed_endpoints {
+                if !API_ENDPOINTS.contains(&endpoint.as_str()) {
+                    problems.push(format!(
+                        "auth client {:?} allows unknown endpoint {:?} (expected one of {})",
+                        client.name,
+                        endpoint,
+                        API_ENDPOINTS.join(", ")
+                    ));
+                }
+            }
+        }
+        if self.auth.admin_keys.iter().any(|key| is_short_secret(key)) {
+            problems.push(format!(
+                "auth.admin_keys must be at least {MIN_SECRET_LEN} characters long"
+            ));
+        }
+        for (name, bucket) in [
+            ("per_key", &self.rate_limit.per_key),
+            ("per_ip", &self.rate_limit.per_ip),
+        ] {
+            if !(bucket.capacity >= 1.0 && bucket.refill_per_sec > 0.0) {
+                problems.push(format!(
+                    "rate_limit.{name} needs a capacity of at least 1 and a positive refill_per_sec"
+                ));
+            }
+        }
+        let (hit_cost, miss_cost) = (
+            self.rate_limit.cache_hit_cost,
+            self.rate_limit.cache_miss_cost,
+        );
+        if !(hit_cost >= 0.0 && miss_cost >= hit_cost) {
+            problems.push(String::from(
+                "rate_limit.cache_hit_cost must be non-negative and at most rate_limit.cache_miss_cost",
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.telemetry.sample_ratio) {
+            problems.push(String::from(
+                "telemetry.sample_ratio must be between 0 and 1",
+            ));
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            bail!("invalid configuration:\n  - {}", problems.join("\n  - "))
+        }
+    }
+
+    /// The default budget limits with any configured endpoints replaced.
+    pub(crate) fn budget_config(&self) -> BudgetConfig {
+        let mut budget = BudgetConfig::default();
+        budget.limits.extend(self.budget.clone());
+        budget
+    }
+}
+
+// keys shorter than this could not be redacted from logs.
+fn is_short_secret(key: &str) -> bool {
+    key.trim().len() < MIN_SECRET_LEN
+}
+
+// Applies WEATHER__SECTION__FIELD overrides, plus OWM_KEY for owm.api_key.
+// Values are parsed as TOML when possible and kept as strings otherwise, and
+// always kept as strings for owm.api_key and fields the file sets to a string.
+fn apply_env_overrides(
+    table: &mut Table,
+    vars: impl Iterator<Item = (String, String)>,
+) -> anyhow::Result<()> {
+    for (name, raw) in vars {
+        let path: Vec<String> = if name == "OWM_KEY" {
+            vec![String::from("owm"), String::from("api_key")]
+        } else if let Some(path) = name.strip_prefix(ENV_PREFIX) {
+            path.split("__").map(|part| part.to_lowercase()).collect()
+        } else {
+            continue;
+        };
+        let (field, sections) = path.split_last().unwrap();
+        let mut current = &mut *table;
+        for section in sections {
+            current = current
+                .entry(section.clone())
+                .or_insert_with(|| Value::Table(Table::new()))
+                .as_table_mut()
+                .ok_or_else(|| anyhow!("{name} overrides a value that is not a table"))?;
+        }
+
+        // keys may well be all digits, which must not turn them into numbers.
+        let is_string = field == "api_key" || current.get(field).is_some_and(Value::is_str);
+        let value = if is_string {
+            Value::String(raw)
+        } else {
+            format!("value = {raw}")
+                .parse::<Table>()
+                .ok()
+                .and_then(|mut parsed| parsed.remove("value"))
+                .unwrap_or(Value::String(raw))
+        };
+        current.insert(field.clone(), value);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_env_overrides_file_values() {
+    let mut table = "[server]\nport = 8080\n[owm]\napi_key = \"from-file\"\n"
+        .parse::<Table>()
+        .unwrap();
+    let vars = vec![
+        (String::from("WEATHER__SERVER__PORT"), String::from("9090")),
+        (
+            String::from("WEATHER__CACHE__RADIUS_KM"),
+            String::from("2.5"),
+        ),
+        (String::from("OWM_KEY"), String::from("from-env")),
+        (
+            String::from("WEATHER__AUTH__CLIENTS"),
+            String::from(r#"[{ name = "app", key = "client-key" }]"#),
+        ),
+        (String::from("PATH"), String::from("/usr/bin")),
+    ];
+    apply_env_overrides(&mut table, vars.into_iter()).unwrap();
+    let config = Config::deserialize(Value::Table(table)).unwrap();
+    assert_eq!(config.server.port, 9090);
+    assert_eq!(config.cache.radius_km, 2.5);
+    assert_eq!(config.owm.api_key, "from-env");
+    assert_eq!(config.auth.clients[0].key, "client-key");
+    assert_eq!(config.cache.ttl_minutes, 15);
+}
+
+#[test]
+fn test_env_overrides_keep_numeric_keys_as_strings() {
+    let mut table = "[owm]\nkeys_file = \"keys.txt\"\n"
+        .parse::<Table>()
+        .unwrap();
+    let vars = vec![
+        (
+            String::from("OWM_KEY"),
+            String::from("12345678901234567890"),
+        ),
+        (
+            String::from("WEATHER__OWM__KEYS_FILE"),
+            String::from("2024"),
+        ),
+    ];
+    apply_env_overrides(&mut table, vars.into_iter()).unwrap();
+    let config = Config::deserialize(Value::Table(table)).unwrap();
+    assert_eq!(config.owm.api_key, "12345678901234567890");
+    assert_eq!(config.owm.keys_file.as_deref(), Some("2024"));
+}
+
+#[test]
+fn test_validate_reports_all_problems() {
+    let mut config = Config::default();
+    config.auth.enabled = false;
+    config.cache.radius_km = -1.0;
+    config.cache.aqi_ttl_minutes = 0;
+    let err = config.validate().unwrap_err().to_string();
+    assert!(err.contains("no OWM key"));
+    assert!(err.contains("cache.radius_km"));
+    assert!(err.contains("cache.aqi_ttl_minutes"));
+
+    config.owm.api_key = String::from("owm-api-key");
+    config.cache.radius_km = 10.0;
+    config.cache.aqi_ttl_minutes = 60;
+    assert!(config.validate().is_ok());
+}
+
+#[test]
+fn test_validate_rejects_short_keys() {
+    let mut config = Config::default();
+    config.owm.api_key = String::from("owm");
+    config.auth.clients = vec![ClientConfig {
+        name: String::from("app"),
+        key: String::from("abc"),
+        allowed_endpoints: vec![],
+        daily_quota: None,
+    }];
+    config.auth.admin_keys = vec![String::from("admin")];
+    let err = config.validate().unwrap_err().to_string();
+    assert!(err.contains("OWM keys must be at least 8 characters"));
+    assert!(err.contains("auth client \"app\" has a key shorter than 8 characters"));
+    assert!(err.contains("auth.admin_keys must be at least 8 characters"));
+    // the message names what is wrong without repeating the keys.
+    assert!(!err.contains("abc"));
+}
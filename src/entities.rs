@@ -1,13 +1,55 @@
+use serde::Serialize;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
 pub struct Location {
     pub latitude: f64,
     pub longitude: f64,
 }
 
+#[cfg(test)]
 impl Location {
-    pub fn default() -> Self {
+    pub(crate) fn at(latitude: f64, longitude: f64) -> Self {
         Self {
-            latitude: 0.0,
-            longitude: 0.0,
+            latitude,
+            longitude,
         }
     }
 }
+
+/// Whether a response came from the cache, recorded in the request extensions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheStatus {
+    Hit,
+    Miss,
+    // served expired data because OWM could not be called or failed.
+    Stale,
+    // estimated from nearby cached data.
+    Interpolated,
+}
+
+impl CacheStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CacheStatus::Hit => "hit",
+            CacheStatus::Miss => "miss",
+            CacheStatus::Stale => "stale",
+            CacheStatus::Interpolated => "interpolated",
+        }
+    }
+
+    /// The status of a response assembled from several cache lookups: a miss if
+    /// anything was fetched, else stale if anything expired was served, else
+    /// interpolated if anything was estimated.
+    pub fn combine(statuses: impl IntoIterator<Item = CacheStatus>) -> Self {
+        statuses
+            .into_iter()
+            .max_by_key(|status| match status {
+                CacheStatus::Hit => 0,
+                CacheStatus::Interpolated => 1,
+                CacheStatus::Stale => 2,
+                CacheStatus::Miss => 3,
+            })
+            .unwrap_or(CacheStatus::Hit)
+    }
+}
@@ -1,4 +1,11 @@
-use actix_web::{error, http::StatusCode};
+use actix_web::{
+    error,
+    http::{header::RETRY_AFTER, StatusCode},
+    HttpResponse,
+};
+
+use crate::{redact::Redactor, upstream::UpstreamError};
+
 pub trait IntoHttpError<T> {
     fn http_error(
         self,
@@ -23,9 +30,70 @@ impl<T, E: std::fmt::Debug> IntoHttpError<T> for core::result::Result<T, E> {
         match self {
             Ok(val) => Ok(val),
             Err(err) => {
-                tracing::error!("http_error: {:?}", err);
+                // error chains can embed upstream URLs, which carry the OWM key.
+                let err = format!("{:?}", err);
+                tracing::error!("http_error: {}", Redactor::global().redact(&err));
                 Err(error::InternalError::new(message.to_string(), status_code).into())
             }
         }
     }
 }
+
+/// An expected error, e.g. a bad request, answered without being logged.
+pub fn rejected(message: &'static str, status_code: StatusCode) -> actix_web::Error {
+    error::InternalError::new(message, status_code).into()
+}
+
+pub trait IntoUpstreamHttpError<T> {
+    /// Like `http_internal_error`, but errors raised by the upstream guards
+    /// (e.g. an open circuit breaker) keep their own status code, and an
+    /// exhausted call budget says when to retry.
+    fn http_upstream_error(self, message: &str) -> core::result::Result<T, actix_web::Error>;
+}
+
+impl<T> IntoUpstreamHttpError<T> for anyhow::Result<T> {
+    fn http_upstream_error(self, message: &str) -> core::result::Result<T, actix_web::Error> {
+        let upstream = self
+            .as_ref()
+            .err()
+            .and_then(|err| err.downcast_ref::<UpstreamError>())
+            .map(|err| (err.status_code(), err.retry_after_secs()));
+        match upstream {
+            Some((status_code, Some(retry_after_secs))) => {
+                self.http_error(message, status_code).map_err(|_| {
+                    let response = HttpResponse::build(status_code)
+                        .insert_header((RETRY_AFTER, retry_after_secs))
+                        .body(message.to_string());
+                    error::InternalError::from_response(message.to_string(), response).into()
+                })
+            }
+            Some((status_code, None)) => self.http_error(message, status_code),
+            None => self.http_internal_error(message),
+        }
+    }
+}
+
+#[test]
+fn test_exhausted_budget_sets_retry_after() {
+    use crate::upstream::Endpoint;
+
+    let result: anyhow::Result<()> = Err(UpstreamError::BudgetExhausted {
+        endpoint: Endpoint::OneCall,
+        retry_after_secs: 42,
+    }
+    .into());
+    let response = result
+        .http_upstream_error("could not fetch weather")
+        .unwrap_err()
+        .error_response();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(response.headers().get(RETRY_AFTER).unwrap(), "42");
+
+    let result: anyhow::Result<()> = Err(UpstreamError::CircuitOpen(Endpoint::OneCall).into());
+    let response = result
+        .http_upstream_error("x")
+        .unwrap_err()
+        .error_response();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert!(response.headers().get(RETRY_AFTER).is_none());
+}
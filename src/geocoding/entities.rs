@@ -1,10 +1,12 @@
-use crate::{weather::entities::ProtoAdapter, weather_proto::weather_message};
+use crate::{cache::CacheValue, weather::entities::ProtoAdapter, weather_proto::weather_message};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub(crate) struct DoGeocodeResp {
     pub(crate) name: String,
+    // OWM leaves this out for places without translated names.
+    #[serde(default)]
     pub(crate) local_names: HashMap<String, String>,
     pub(crate) lat: f64,
     pub(crate) lon: f64,
@@ -19,6 +21,8 @@ pub struct ReverseGeocode {
     pub state: String,
     pub latitude: f64,
     pub longitude: f64,
+    // the name in other languages, by language code.
+    pub local_names: HashMap<String, String>,
 }
 
 impl ReverseGeocode {
@@ -29,6 +33,48 @@ impl ReverseGeocode {
             state: "".to_string(),
             latitude: 0.0,
             longitude: 0.0,
+            local_names: HashMap::new(),
+        }
+    }
+
+    /// The proto with the name in `lang` if OWM knows it, else the default name.
+    /// OWM keys names by bare language, so `pt_br` falls back to `pt`.
+    pub(crate) fn to_proto_in(&self, lang: Option<&str>) -> weather_message::ReverseGeocode {
+        let mut proto = self.to_proto();
+        if let Some(name) = lang.and_then(|lang| self.local_name(lang)) {
+            proto.name = name.clone();
+        }
+        proto
+    }
+
+    fn local_name(&self, lang: &str) -> Option<&String> {
+        self.local_names.get(lang).or_else(|| {
+            let (language, _region) = lang.split_once('_')?;
+            self.local_names.get(language)
+        })
+    }
+}
+
+impl CacheValue for ReverseGeocode {
+    fn describe(&self) -> String {
+        [&self.name, &self.state, &self.country]
+            .into_iter()
+            .filter(|part| !part.is_empty())
+            .cloned()
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+impl From<&weather_message::ReverseGeocode> for ReverseGeocode {
+    fn from(proto: &weather_message::ReverseGeocode) -> Self {
+        Self {
+            name: proto.name.clone(),
+            country: proto.country.clone(),
+            state: proto.state.clone(),
+            latitude: proto.latitude,
+            longitude: proto.longitude,
+            local_names: HashMap::new(),
         }
     }
 }
@@ -47,3 +93,22 @@ impl ProtoAdapter for ReverseGeocode {
         }
     }
 }
+
+#[test]
+fn test_local_name_falls_back_to_language() {
+    let place = ReverseGeocode {
+        name: String::from("Lisbon"),
+        local_names: HashMap::from([
+            (String::from("pt"), String::from("Lisboa")),
+            (String::from("zh_cn"), String::from("里斯本")),
+        ]),
+        ..ReverseGeocode::default()
+    };
+    let name = |lang| place.to_proto_in(lang).name;
+    assert_eq!(name(Some("pt")), "Lisboa");
+    assert_eq!(name(Some("pt_br")), "Lisboa");
+    assert_eq!(name(Some("zh_cn")), "里斯本");
+    assert_eq!(name(Some("de")), "Lisbon");
+    assert_eq!(name(Some("de_at")), "Lisbon");
+    assert_eq!(name(None), "Lisbon");
+}
@@ -1,85 +1,177 @@
 use actix_web::web;
-use anyhow::{anyhow, Ok};
-use chrono::Utc;
-use reqwest::StatusCode;
-use tracing::info;
+use reqwest::Url;
 
-use crate::{entities::Location, weather::utils::haversine, APIKey, AppState};
+use crate::{
+    cache::Fetched,
+    entities::{CacheStatus, Location},
+    upstream::{self, keys::APIKey, Endpoint, UpstreamError},
+    AppState,
+};
 
 use super::entities::{DoGeocodeResp, ReverseGeocode};
 
-pub(crate) async fn do_geocode(keys: &APIKey, place_name: String) -> anyhow::Result<Location> {
-    let owm_query = format!(
-        "http://api.openweathermap.org/geo/1.0/direct?q={}&limit=1&appid={}",
-        place_name, keys.owm_key
-    );
-    let response = reqwest::get(owm_query).await?;
-
-    if !StatusCode::is_success(&response.status()) {
-        // Our request failed for some reason, we will try again later.
-        return Ok(Location::default());
-    }
+pub(crate) async fn do_geocode(
+    place_name: String,
+    data: &web::Data<AppState>,
+) -> anyhow::Result<Location> {
+    // the place name is user input, so it is encoded rather than formatted
+    // into the query where it could add parameters of its own.
+    let url = Url::parse_with_params(
+        &format!("{}/geo/1.0/direct", data.config.owm.base_url),
+        &[("q", place_name.as_str()), ("limit", "1")],
+    )?;
+    let owm_query = |keys: &APIKey| {
+        let mut url = url.clone();
+        url.query_pairs_mut().append_pair("appid", &keys.owm_key);
+        url.into()
+    };
+    let response = data.http_client.get(Endpoint::Geo, owm_query).await?;
+    let response = upstream::success(Endpoint::Geo, response)?;
 
-    let response_mapping = response.json::<Vec<DoGeocodeResp>>().await?;
+    let response_mapping = response
+        .json::<Vec<DoGeocodeResp>>()
+        .await
+        .map_err(reqwest::Error::without_url)?;
 
     let loc = response_mapping
-        .get(0)
-        .ok_or(anyhow!("response vec is empty"))?;
+        .first()
+        .ok_or(UpstreamError::NoData(Endpoint::Geo))?;
     Ok(Location {
         latitude: loc.lat,
         longitude: loc.lon,
     })
 }
 
+/// The place name of `location`, from the reverse geocode tier of the cache
+/// when possible.
+#[tracing::instrument(
+    skip(data),
+    fields(cache.status = tracing::field::Empty, cache.distance_km = tracing::field::Empty)
+)]
 pub(crate) async fn do_reverse_geocode(
-    keys: &APIKey,
     location: &Location,
     data: &web::Data<AppState>,
-) -> anyhow::Result<ReverseGeocode> {
-    {
-        let kdtree = data.kdtree.lock().unwrap();
-        let rev = data.cached_data.lock().unwrap();
-
-        let query = [location.latitude, location.longitude];
-        let (dist, nearest_idx) = kdtree.nearest_one(&query, &haversine);
-        info!("Distance from given point {}km", dist);
-        info!("Points in kdtree {}", kdtree.size());
-        if dist < 10.0 {
-            // return result from hashmap
-            if let Some(cached_res) = rev.get(&nearest_idx) {
-                if cached_res.expiry > Utc::now() {
-                    return Ok(cached_res.reverse_geocode.clone());
-                }
-            }
-        }
-    }
+) -> anyhow::Result<(ReverseGeocode, CacheStatus)> {
+    let cache = &data.caches.reverse_geocode;
+    cache
+        .get_or_fetch(
+            location,
+            |_| true,
+            &data.metrics,
+            || async {
+                Ok(match fetch_reverse_geocode(location, data).await? {
+                    Some(reverse_geocode) => Fetched::Cache(reverse_geocode, cache.config().ttl),
+                    // OWM knows no place here, e.g. out at sea; the rest of the
+                    // response is still served, with an empty place name.
+                    None => Fetched::Uncached(ReverseGeocode::default()),
+                })
+            },
+        )
+        .await
+}
+
+// None if OWM has no place at `location`.
+async fn fetch_reverse_geocode(
+    location: &Location,
+    data: &web::Data<AppState>,
+) -> anyhow::Result<Option<ReverseGeocode>> {
     // construct query URL
-    let owm_query = format!(
-        "http://api.openweathermap.org/geo/1.0/reverse?lat={}&lon={}&limit=1&appid={}",
-        location.latitude, location.longitude, keys.owm_key
-    );
+    let owm_query = |keys: &APIKey| {
+        format!(
+            "{}/geo/1.0/reverse?lat={}&lon={}&limit=1&appid={}",
+            data.config.owm.base_url, location.latitude, location.longitude, keys.owm_key
+        )
+    };
 
     // make request
-    let response = reqwest::get(owm_query).await?;
-
-    // Our request failed for some reason, we will try again later.
-    if !StatusCode::is_success(&response.status()) {
-        return Ok(ReverseGeocode::default());
-    }
+    let response = data.http_client.get(Endpoint::Geo, owm_query).await?;
+    // a stale entry can stand in while OWM is failing.
+    let response = upstream::success(Endpoint::Geo, response)?;
 
     // deserialize response
-    let response_mapping = response.json::<Vec<DoGeocodeResp>>().await.unwrap();
+    let response_mapping = response
+        .json::<Vec<DoGeocodeResp>>()
+        .await
+        .map_err(reqwest::Error::without_url)?;
 
     // return first response
-    let loc = response_mapping
-        .get(0)
-        .ok_or(anyhow!("response vec is empty"))?;
+    let Some(loc) = response_mapping.first() else {
+        return Ok(None);
+    };
 
-    Ok(ReverseGeocode {
+    Ok(Some(ReverseGeocode {
         name: loc.name.clone(),
         country: loc.country.clone(),
         state: loc.state.clone().unwrap_or(String::from("")),
         latitude: loc.lat,
         longitude: loc.lon,
-    })
+        local_names: loc.local_names.clone(),
+    }))
+}
+
+#[actix_web::test]
+async fn test_serves_stale_place_name_when_owm_fails() {
+    let mut config = crate::config::Config::default();
+    config.owm.api_key = String::from("owm-key");
+    config.owm.base_url = crate::fake_owm(vec!["503 Service Unavailable"]).await;
+    config.http.max_retries = 0;
+    let data = crate::test_state(config);
+    let location = Location::at(37.5, -122.0);
+
+    let err = do_reverse_geocode(&location, &data).await.unwrap_err();
+    assert!(crate::upstream::is_unavailable(&err));
+
+    data.caches.reverse_geocode.insert(
+        location,
+        ReverseGeocode {
+            name: String::from("Fremont"),
+            ..ReverseGeocode::default()
+        },
+        chrono::Duration::minutes(-1),
+        None,
+    );
+    let (reverse_geocode, status) = do_reverse_geocode(&location, &data).await.unwrap();
+    assert_eq!(status, CacheStatus::Stale);
+    assert_eq!(reverse_geocode.name, "Fremont");
+}
+
+#[actix_web::test]
+async fn test_no_place_at_sea() {
+    let mut config = crate::config::Config::default();
+    config.owm.api_key = String::from("owm-key");
+    config.owm.base_url =
+        crate::fake_owm(vec!["200 OK\r\ncontent-type: application/json\r\n\r\n[]"]).await;
+    let data = crate::test_state(config);
+    let location = Location::at(0.0, -30.0);
+
+    let (reverse_geocode, status) = do_reverse_geocode(&location, &data).await.unwrap();
+    assert_eq!(status, CacheStatus::Miss);
+    assert!(reverse_geocode.name.is_empty());
+    assert_eq!(crate::cache::Tier::len(&data.caches.reverse_geocode), 0);
+}
+
+#[actix_web::test]
+async fn test_geocode_surfaces_owm_failures() {
+    let mut config = crate::config::Config::default();
+    config.owm.api_key = String::from("owm-key");
+    config.owm.base_url = crate::fake_owm(vec![
+        "503 Service Unavailable",
+        "200 OK\r\ncontent-type: application/json\r\n\r\n[]",
+    ])
+    .await;
+    config.http.max_retries = 0;
+    let data = crate::test_state(config);
+
+    let err = do_geocode(String::from("Paris&limit=5"), &data)
+        .await
+        .unwrap_err();
+    assert!(crate::upstream::is_unavailable(&err));
+
+    let err = do_geocode(String::from("Nowhere"), &data)
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<UpstreamError>(),
+        Some(UpstreamError::NoData(Endpoint::Geo))
+    ));
 }
@@ -1,2 +1,2 @@
+pub mod entities;
 pub mod methods;
-pub mod entities;
\ No newline at end of file
@@ -0,0 +1,74 @@
+use std::collections::HashMap;
+
+use actix_web::{get, web, HttpResponse, Responder};
+use serde::Serialize;
+
+use crate::{
+    upstream::{breaker::BreakerStatus, client::LastCall, Endpoint},
+    AppState,
+};
+
+#[derive(Debug, Serialize)]
+struct Readiness {
+    ready: bool,
+    config_loaded: bool,
+    cache_reachable: bool,
+    usable_keys: usize,
+    breakers: HashMap<Endpoint, BreakerStatus>,
+    last_upstream_calls: HashMap<Endpoint, LastCall>,
+}
+
+pub(crate) fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(healthz).service(readyz);
+}
+
+/// Liveness: the process is up and serving requests.
+#[get("/healthz")]
+async fn healthz() -> impl Responder {
+    HttpResponse::Ok().json(serde_json::json!({ "status": "ok" }))
+}
+
+/// Readiness: the instance can serve requests, i.e. its config is loaded and
+/// the cache is usable. Answers 503 otherwise. Breaker and key state are
+/// reported but do not count: an OWM outage or spent quota hits every replica
+/// at once, and pulling them all would leave nothing to serve stale entries.
+#[get("/readyz")]
+async fn readyz(data: web::Data<AppState>) -> impl Responder {
+    // a poisoned lock means a request panicked while holding the cache.
+    let cache_reachable = data.caches.tiers().iter().all(|tier| tier.is_reachable());
+    // the config is validated before the server starts.
+    let config_loaded = true;
+
+    let readiness = Readiness {
+        ready: config_loaded && cache_reachable,
+        config_loaded,
+        cache_reachable,
+        usable_keys: data.http_client.keys.usable_count(),
+        breakers: data.http_client.breakers.statuses(),
+        last_upstream_calls: data.http_client.last_calls(),
+    };
+    if readiness.ready {
+        HttpResponse::Ok().json(readiness)
+    } else {
+        HttpResponse::ServiceUnavailable().json(readiness)
+    }
+}
+
+#[actix_web::test]
+async fn test_ready_without_usable_keys() {
+    use actix_web::{http::StatusCode, test, App};
+
+    let mut config = crate::config::Config::default();
+    config.owm.api_key = String::from("owm-key");
+    let data = crate::test_state(config);
+    let key = data.http_client.keys.pick().unwrap();
+    data.http_client.keys.mark_exhausted(&key, None);
+    let app = test::init_service(App::new().app_data(data).configure(configure)).await;
+
+    let response =
+        test::call_service(&app, test::TestRequest::get().uri("/readyz").to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: serde_json::Value = test::read_body_json(response).await;
+    assert_eq!(body["ready"], true);
+    assert_eq!(body["usable_keys"], 0);
+}
@@ -0,0 +1,351 @@
+// mod database_utils;
+mod admin;
+mod auth;
+pub mod cache;
+mod cli;
+mod config;
+pub mod entities;
+mod errors;
+mod geocoding;
+mod health;
+mod metrics;
+mod redact;
+mod snapshot;
+mod telemetry;
+mod upstream;
+mod validation;
+mod weather;
+
+use crate::auth::{
+    middleware::{ClientAuth, RateLimit},
+    ratelimit::RateLimiter,
+    ClientStore,
+};
+use crate::cache::{radius::RadiusModel, Caches};
+use crate::cli::Command;
+use crate::config::Config;
+use crate::errors::IntoUpstreamHttpError;
+use crate::metrics::{Metrics, RequestMetrics};
+use crate::telemetry::RequestTracing;
+use crate::upstream::client::UpstreamClient;
+use crate::upstream::keys::{watch_keys_file, KeyRing};
+use crate::weather::entities::ProtoAdapter as _;
+use crate::weather::methods::do_weather_query;
+use crate::weather::sections::Sections;
+use crate::weather::warming::{keep_warm, read_locations_file};
+
+use actix_web::{get, web, App, HttpMessage, HttpRequest, HttpServer, Responder};
+use entities::CacheStatus;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+// the generated code allows `box_pointers`, a lint newer compilers have removed.
+#[allow(renamed_and_removed_lints)]
+mod weather_proto {
+    include!(concat!(env!("OUT_DIR"), "/proto/mod.rs"));
+}
+
+#[derive(Debug)]
+struct AppState {
+    caches: Caches,
+    http_client: UpstreamClient,
+    clients: ClientStore,
+    key_limiter: RateLimiter,
+    ip_limiter: RateLimiter,
+    metrics: Arc<Metrics>,
+    config: Config,
+}
+
+impl AppState {
+    fn new(config: Config, keys: Arc<KeyRing>, metrics: Arc<Metrics>) -> anyhow::Result<Self> {
+        let http_client = UpstreamClient::new(
+            config.http.connect_timeout(),
+            config.http.request_timeout(),
+            config.http.retry_policy(),
+            config.breaker.breaker_config(),
+            config.budget_config(),
+            keys,
+            metrics.clone(),
+        )?;
+        let radius_model = RadiusModel::load(&config.cache.adaptive_radius)?;
+        Ok(Self {
+            caches: Caches::new(&config.cache, radius_model),
+            http_client,
+            clients: ClientStore::new(config.auth.clients.clone(), config.auth.admin_keys.clone()),
+            key_limiter: RateLimiter::new(config.rate_limit.per_key),
+            ip_limiter: RateLimiter::new(config.rate_limit.per_ip),
+            metrics,
+            config,
+        })
+    }
+}
+
+// state for handler tests, calling OWM at `config.owm.base_url`.
+#[cfg(test)]
+fn test_state(config: Config) -> web::Data<AppState> {
+    let keys = KeyRing::new(
+        config.owm.all_keys().unwrap(),
+        config.owm.key_strategy,
+        Duration::from_secs(config.owm.key_cooldown_secs),
+    )
+    .unwrap();
+    let metrics = Arc::new(Metrics::new().unwrap());
+    web::Data::new(AppState::new(config, Arc::new(keys), metrics).unwrap())
+}
+
+// an OWM stand-in that answers requests with `responses` in order, repeating
+// the last one, e.g. "503 Service Unavailable". A response may carry headers
+// and a body after the status, separated as in HTTP. Returns its base URL.
+#[cfg(test)]
+async fn fake_owm(responses: Vec<&'static str>) -> String {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let base_url = format!("http://{}", listener.local_addr().unwrap());
+    actix_web::rt::spawn(async move {
+        for served in 0.. {
+            let Ok((mut stream, _)) = listener.accept().await else {
+                break;
+            };
+            let mut request = [0; 4096];
+            let _ = stream.read(&mut request).await;
+            let response = responses[served.min(responses.len() - 1)];
+            let (head, body) = response.split_once("\r\n\r\n").unwrap_or((response, ""));
+            let _ = stream
+                .write_all(
+                    format!(
+                        "HTTP/1.1 {head}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+                        body.len()
+                    )
+                    .as_bytes(),
+                )
+                .await;
+        }
+    });
+    base_url
+}
+
+#[get("/hello/{name}")]
+async fn greet(name: web::Path<String>) -> impl Responder {
+    format!("Hello {name}!")
+}
+
+#[get("/weather/{latitude}/{longitude}/{units}")]
+#[tracing::instrument(skip(data, req))]
+async fn parse_lat_long(
+    full_query: web::Path<(f64, f64, String)>,
+    data: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let (lat, long, units) = full_query.into_inner();
+    let location = validation::location(lat, long)?;
+    let units = validation::units(&units)?;
+    // figure out a better way to find a location in the database that is more efficient than looping over the entire db
+    // anyway figure out if the db contains a location within 2km of the received lat/long using `math::haversine`
+    // if contains, format the json with the relevant entry from the db.
+    // if not, query owm and store the result of the api call in the db, then return the information
+    // the client needs.
+    let full_proto_response = do_weather_query(location, units, Sections::ALL, None, data).await;
+    full_proto_response
+        .map(|(weather, cache_status)| {
+            req.extensions_mut().insert(cache_status);
+            weather
+        })
+        .http_upstream_error("could not fetch weather")
+}
+
+#[derive(Debug, Deserialize)]
+struct WeatherQuery {
+    lat: f64,
+    lon: f64,
+    // standard when not given.
+    units: Option<String>,
+    // the language of the place name.
+    lang: Option<String>,
+    // every section when not given.
+    include: Option<String>,
+}
+
+/// Like `parse_lat_long`, but only the sections listed in `include` are
+/// looked up and returned.
+#[get("/weather")]
+#[tracing::instrument(skip(data, req))]
+async fn weather_v2(
+    query: web::Query<WeatherQuery>,
+    data: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let location = validation::location(query.lat, query.lon)?;
+    let units = validation::units(query.units.as_deref().unwrap_or("standard"))?;
+    let lang = query.lang.as_deref().map(validation::lang).transpose()?;
+    let sections = match &query.include {
+        Some(include) => validation::sections(include)?,
+        None => Sections::ALL,
+    };
+    do_weather_query(location, units, sections, lang.as_deref(), data)
+        .await
+        .map(|(weather, cache_status)| {
+            req.extensions_mut().insert(cache_status);
+            weather
+        })
+        .http_upstream_error("could not fetch weather")
+}
+
+#[get("/geocode/{place}")]
+#[tracing::instrument(skip(data, req))]
+async fn geocode(
+    full_query: web::Path<String>,
+    data: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let response = geocoding::methods::do_geocode(full_query.into_inner(), &data).await;
+    response
+        .map(|resp| {
+            // forward geocodes are not cached.
+            req.extensions_mut().insert(CacheStatus::Miss);
+            format!("{}, {}", resp.latitude, resp.longitude)
+        })
+        .http_upstream_error("something went wrong")
+}
+
+#[get("/reversegeocode/{latitude}/{longitude}")]
+#[tracing::instrument(skip(data, req))]
+async fn reverse_geocode(
+    full_query: web::Path<(f64, f64)>,
+    data: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let (lat, long) = full_query.into_inner();
+    let loc = validation::location(lat, long)?;
+
+    let resp = geocoding::methods::do_reverse_geocode(&loc, &data).await;
+    resp.map(|(response, cache_status)| {
+        req.extensions_mut().insert(cache_status);
+        response.to_proto().to_string()
+    })
+    .http_upstream_error("something went wrong")
+}
+
+/// Runs the server, or the subcommand given on the command line.
+pub async fn run() -> anyhow::Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match cli::parse(&args)? {
+        Command::Serve => serve().await,
+        command => cli::run(command).await,
+    }
+}
+
+async fn serve() -> anyhow::Result<()> {
+    // fail fast on a missing key or bad values instead of on the first request.
+    let config = Config::load()?;
+
+    // install global tracing collector, exporting spans over OTLP if configured
+    telemetry::init(&config.logging, &config.telemetry)?;
+    redact::install_panic_hook();
+    info!("Initialized tracing");
+
+    let keys = Arc::new(KeyRing::new(
+        config.owm.all_keys()?,
+        config.owm.key_strategy,
+        Duration::from_secs(config.owm.key_cooldown_secs),
+    )?);
+    info!("Loaded {} OWM keys", keys.len());
+    // cancelled once the server has stopped.
+    let mut background_tasks = vec![];
+    if let Some(path) = &config.owm.keys_file {
+        let keys = keys.clone();
+        let static_keys = config.owm.static_keys();
+        let path = PathBuf::from(path);
+        let interval = Duration::from_secs(config.owm.keys_reload_secs);
+        background_tasks.push(actix_web::rt::spawn(async move {
+            watch_keys_file(&keys, static_keys, path, interval).await
+        }));
+    }
+
+    let metrics = Arc::new(Metrics::new()?);
+
+    info!(
+        "Starting HTTP server on {}:{}",
+        config.server.host, config.server.port
+    );
+    let bind_addr = (config.server.host.clone(), config.server.port);
+    let access_log = config.logging.access_log;
+    let shutdown_timeout = config.server.shutdown_timeout_secs;
+    let web_data = web::Data::new(AppState::new(config, keys, metrics)?);
+    let app_data = web_data.clone();
+    if let Some(path) = &web_data.config.cache.snapshot_path {
+        let path = Path::new(path);
+        // a missing or unreadable snapshot only means starting with a cold cache.
+        if path.exists() {
+            match snapshot::read_snapshot(path)
+                .and_then(|snapshot| snapshot::restore_snapshot(&web_data, snapshot))
+            {
+                Ok(entries) => info!("Restored {} cache entries from {}", entries, path.display()),
+                Err(err) => warn!("Starting with an empty cache: {:#}", err),
+            }
+        }
+    }
+    let data = web_data.clone();
+    let interval = Duration::from_secs(web_data.config.cache.eviction_interval_secs);
+    background_tasks.push(actix_web::rt::spawn(async move {
+        cache::evict_expired(&data.caches, interval).await
+    }));
+    if let Some(path) = &web_data.config.warming.locations_file {
+        let locations = read_locations_file(Path::new(path))?;
+        let data = web_data.clone();
+        let config = web_data.config.warming.clone();
+        background_tasks.push(actix_web::rt::spawn(keep_warm(data, locations, config)));
+    }
+    HttpServer::new(move || {
+        App::new()
+            .app_data(app_data.clone())
+            .wrap(RequestMetrics)
+            .wrap(RequestTracing { access_log })
+            .service(metrics::export_metrics)
+            .configure(health::configure)
+            .route("/hello", web::get().to(|| async { "Hello World!" }))
+            .service(greet)
+            .service(
+                web::scope("/v1/api")
+                    .wrap(RateLimit)
+                    // registered last so it runs first and RateLimit sees the client.
+                    .wrap(ClientAuth)
+                    .service(geocode)
+                    .service(reverse_geocode)
+                    .service(parse_lat_long),
+            )
+            .service(
+                web::scope("/v2")
+                    .wrap(RateLimit)
+                    .wrap(ClientAuth)
+                    .service(weather_v2),
+            )
+            .configure(admin::configure)
+    })
+    // on SIGTERM the server stops accepting connections and gives in-flight
+    // requests up to shutdown_timeout to finish. SIGINT and SIGQUIT stop it
+    // at once; the snapshot below is written either way.
+    .shutdown_timeout(shutdown_timeout)
+    .bind(bind_addr)?
+    .run()
+    .await?;
+
+    info!("Server stopped, shutting down");
+    for task in background_tasks {
+        task.abort();
+    }
+    if let Some(path) = &web_data.config.cache.snapshot_path {
+        match snapshot::write_snapshot(&web_data, Path::new(path)) {
+            Ok(entries) => info!("Wrote {} cache entries to {}", entries, path),
+            Err(err) => error!("Could not write cache snapshot: {:#}", err),
+        }
+    }
+    telemetry::shutdown();
+    Ok(())
+}
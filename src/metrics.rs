@@ -0,0 +1,223 @@
+use std::{
+    future::{ready, Future, Ready},
+    pin::Pin,
+    time::Instant,
+};
+
+use actix_web::{
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    get, web, Error, HttpResponse, Responder,
+};
+use prometheus::{
+    core::Collector, Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec,
+    Opts, Registry, TextEncoder,
+};
+
+use crate::{entities::CacheStatus, upstream::Endpoint, AppState};
+
+/// Every metric the service exports on /metrics.
+#[derive(Debug)]
+pub(crate) struct Metrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_latency: HistogramVec,
+    http_in_flight: IntGauge,
+    cache_lookups: IntCounterVec,
+    cache_entries: IntGaugeVec,
+    upstream_calls: IntCounterVec,
+    upstream_latency: HistogramVec,
+    upstream_rejected: IntCounterVec,
+}
+
+impl Metrics {
+    pub(crate) fn new() -> anyhow::Result<Self> {
+        let metrics = Self {
+            registry: Registry::new_custom(Some(String::from("weather_api")), None)?,
+            http_requests: IntCounterVec::new(
+                Opts::new("http_requests_total", "HTTP requests by route and status"),
+                &["route", "method", "status"],
+            )?,
+            http_latency: HistogramVec::new(
+                HistogramOpts::new("http_request_duration_seconds", "HTTP request latency"),
+                &["route", "method"],
+            )?,
+            http_in_flight: IntGauge::new("http_requests_in_flight", "HTTP requests in flight")?,
+            cache_lookups: IntCounterVec::new(
+                Opts::new("cache_lookups_total", "Cache lookups by cache and result"),
+                &["cache", "result"],
+            )?,
+            cache_entries: IntGaugeVec::new(
+                Opts::new("cache_entries", "Entries in each cache tier"),
+                &["cache"],
+            )?,
+            upstream_calls: IntCounterVec::new(
+                Opts::new("upstream_calls_total", "OWM calls by endpoint and status"),
+                &["endpoint", "status"],
+            )?,
+            upstream_latency: HistogramVec::new(
+                HistogramOpts::new("upstream_call_duration_seconds", "OWM call latency"),
+                &["endpoint"],
+            )?,
+            upstream_rejected: IntCounterVec::new(
+                Opts::new(
+                    "upstream_rejected_total",
+                    "OWM calls not made because of the breaker, budget or keys",
+                ),
+                &["endpoint", "reason"],
+            )?,
+        };
+        let collectors: [Box<dyn Collector>; 8] = [
+            Box::new(metrics.http_requests.clone()),
+            Box::new(metrics.http_latency.clone()),
+            Box::new(metrics.http_in_flight.clone()),
+            Box::new(metrics.cache_lookups.clone()),
+            Box::new(metrics.cache_entries.clone()),
+            Box::new(metrics.upstream_calls.clone()),
+            Box::new(metrics.upstream_latency.clone()),
+            Box::new(metrics.upstream_rejected.clone()),
+        ];
+        for collector in collectors {
+            metrics.registry.register(collector)?;
+        }
+        Ok(metrics)
+    }
+
+    pub(crate) fn record_cache(&self, cache: &str, status: CacheStatus) {
+        self.cache_lookups
+            .with_label_values(&[cache, status.as_str()])
+            .inc();
+    }
+
+    /// Records one HTTP attempt against OWM; `status` is None if no response arrived.
+    pub(crate) fn record_upstream(
+        &self,
+        endpoint: Endpoint,
+        status: Option<u16>,
+        started: Instant,
+    ) {
+        let status = status.map_or(String::from("error"), |status| status.to_string());
+        self.upstream_calls
+            .with_label_values(&[endpoint.as_str(), &status])
+            .inc();
+        self.upstream_latency
+            .with_label_values(&[endpoint.as_str()])
+            .observe(started.elapsed().as_secs_f64());
+    }
+
+    pub(crate) fn record_upstream_rejected(&self, endpoint: Endpoint, reason: &str) {
+        self.upstream_rejected
+            .with_label_values(&[endpoint.as_str(), reason])
+            .inc();
+    }
+
+    fn render(&self) -> anyhow::Result<String> {
+        let mut buffer = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer)?)
+    }
+}
+
+#[get("/metrics")]
+async fn export_metrics(data: web::Data<AppState>) -> impl Responder {
+    for tier in data.caches.tiers() {
+        data.metrics
+            .cache_entries
+            .with_label_values(&[tier.name()])
+            .set(tier.len() as i64);
+    }
+    match data.metrics.render() {
+        Ok(body) => HttpResponse::Ok()
+            .content_type(TextEncoder::new().format_type())
+            .body(body),
+        Err(err) => {
+            tracing::error!("could not render metrics: {:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Counts requests, their latency and the requests in flight, labelled with
+/// the matched route pattern so path parameters do not blow up cardinality.
+pub(crate) struct RequestMetrics;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestMetrics
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequestMetricsMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestMetricsMiddleware { service }))
+    }
+}
+
+pub(crate) struct RequestMetricsMiddleware<S> {
+    service: S,
+}
+
+// decrements the in-flight gauge however the request ends.
+struct InFlight(IntGauge);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+impl<S, B> Service<ServiceRequest> for RequestMetricsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let data = req
+            .app_data::<web::Data<AppState>>()
+            .expect("AppState is registered")
+            .clone();
+        let method = req.method().to_string();
+        let started = Instant::now();
+        data.metrics.http_in_flight.inc();
+        let in_flight = InFlight(data.metrics.http_in_flight.clone());
+
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let _in_flight = in_flight;
+            let result = fut.await;
+            let (route, status) = match &result {
+                Ok(res) => (
+                    res.request()
+                        .match_pattern()
+                        .unwrap_or_else(|| String::from("unmatched")),
+                    res.status().as_u16(),
+                ),
+                // errors here come from middleware rejecting the request (auth, rate limits).
+                Err(err) => (
+                    String::from("rejected"),
+                    err.as_response_error().status_code().as_u16(),
+                ),
+            };
+            data.metrics
+                .http_requests
+                .with_label_values(&[&route, &method, &status.to_string()])
+                .inc();
+            data.metrics
+                .http_latency
+                .with_label_values(&[&route, &method])
+                .observe(started.elapsed().as_secs_f64());
+            result
+        })
+    }
+}
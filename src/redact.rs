@@ -0,0 +1,212 @@
+use std::{
+    borrow::Cow,
+    io,
+    sync::{Arc, OnceLock, RwLock},
+};
+
+use tracing_subscriber::fmt::MakeWriter;
+
+pub(crate) const REDACTED: &str = "[REDACTED]";
+
+// query parameters that carry credentials in URLs we build or receive.
+const SECRET_PARAMS: [&str; 2] = ["appid=", "api_key="];
+
+// shorter secrets are not matched verbatim, they would mangle unrelated output.
+// Configured keys must be at least this long.
+pub(crate) const MIN_SECRET_LEN: usize = 8;
+
+/// Scrubs known secrets (OWM keys, client API keys) and credential query
+/// parameters out of text before it is logged or exported.
+#[derive(Debug, Default)]
+pub(crate) struct Redactor {
+    secrets: RwLock<Vec<String>>,
+}
+
+impl Redactor {
+    /// The process-wide redactor used by the log writer, the panic hook and the
+    /// span exporter.
+    pub(crate) fn global() -> Arc<Redactor> {
+        static GLOBAL: OnceLock<Arc<Redactor>> = OnceLock::new();
+        GLOBAL.get_or_init(Default::default).clone()
+    }
+
+    /// Adds secrets to redact. Secrets are never removed, so output stays clean
+    /// after a key is rotated out.
+    pub(crate) fn add_secrets(&self, secrets: impl IntoIterator<Item = String>) {
+        let mut known = self.secrets.write().unwrap();
+        for secret in secrets {
+            let secret = secret.trim();
+            if secret.len() >= MIN_SECRET_LEN && !known.iter().any(|known| known == secret) {
+                known.push(secret.to_string());
+            }
+        }
+        // a secret containing another must be replaced first.
+        known.sort_by_key(|secret| std::cmp::Reverse(secret.len()));
+    }
+
+    pub(crate) fn redact<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let mut text = Cow::Borrowed(text);
+        for secret in self.secrets.read().unwrap().iter() {
+            if text.contains(secret.as_str()) {
+                text = Cow::Owned(text.replace(secret.as_str(), REDACTED));
+            }
+        }
+        for param in SECRET_PARAMS {
+            if text.contains(param) {
+                text = Cow::Owned(redact_param(&text, param));
+            }
+        }
+        text
+    }
+}
+
+// replaces the value following every occurrence of `param`.
+fn redact_param(text: &str, param: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(param) {
+        let value_start = start + param.len();
+        out.push_str(&rest[..value_start]);
+        rest = &rest[value_start..];
+        let value_len = rest
+            .find(|c: char| matches!(c, '&' | '"' | '\'' | '\\' | '#' | ')') || c.is_whitespace())
+            .unwrap_or(rest.len());
+        if rest[..value_len] != *REDACTED {
+            out.push_str(REDACTED);
+        } else {
+            out.push_str(&rest[..value_len]);
+        }
+        rest = &rest[value_len..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Wraps a log writer so that every line is redacted before it is written.
+pub(crate) struct RedactingMakeWriter<M> {
+    inner: M,
+    redactor: Arc<Redactor>,
+}
+
+impl<M> RedactingMakeWriter<M> {
+    pub(crate) fn new(inner: M, redactor: Arc<Redactor>) -> Self {
+        Self { inner, redactor }
+    }
+}
+
+impl<'a, M: MakeWriter<'a>> MakeWriter<'a> for RedactingMakeWriter<M> {
+    type Writer = RedactingWriter<'a, M::Writer>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        RedactingWriter {
+            inner: self.inner.make_writer(),
+            redactor: &self.redactor,
+        }
+    }
+}
+
+pub(crate) struct RedactingWriter<'a, W> {
+    inner: W,
+    redactor: &'a Redactor,
+}
+
+impl<W: io::Write> io::Write for RedactingWriter<'_, W> {
+    // the fmt layer writes each event with a single call, so secrets are never
+    // split across writes.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let text = String::from_utf8_lossy(buf);
+        self.inner
+            .write_all(self.redactor.redact(&text).as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Logs panics through tracing with secrets redacted, instead of printing the
+/// raw message to stderr.
+pub(crate) fn install_panic_hook() {
+    let redactor = Redactor::global();
+    std::panic::set_hook(Box::new(move |info| {
+        tracing::error!(target: "panic", "{}", redactor.redact(&info.to_string()));
+    }));
+}
+
+#[test]
+fn test_redact_param() {
+    let redactor = Redactor::default();
+    assert_eq!(
+        redactor.redact("GET http://owm/onecall?lat=1&appid=abc&units=metric failed"),
+        "GET http://owm/onecall?lat=1&appid=[REDACTED]&units=metric failed"
+    );
+    assert_eq!(
+        redactor.redact("/v1/api/weather?api_key=xyz"),
+        "/v1/api/weather?api_key=[REDACTED]"
+    );
+    assert_eq!(redactor.redact("nothing to hide"), "nothing to hide");
+}
+
+#[test]
+fn test_logs_never_contain_keys() {
+    use std::sync::Mutex;
+
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let owm_key = "0123456789abcdef0123456789abcdef";
+    let client_key = "client-secret-key";
+    let redactor = Arc::new(Redactor::default());
+    redactor.add_secrets([owm_key.to_string(), client_key.to_string()]);
+
+    let captured = Captured::default();
+    let make_writer = {
+        let captured = captured.clone();
+        move || captured.clone()
+    };
+    for json in [false, true] {
+        let layer = tracing_subscriber::fmt::layer().with_writer(RedactingMakeWriter::new(
+            make_writer.clone(),
+            redactor.clone(),
+        ));
+        let subscriber: Box<dyn tracing::Subscriber + Send + Sync> = if json {
+            Box::new(tracing_subscriber::layer::SubscriberExt::with(
+                tracing_subscriber::registry(),
+                layer.json(),
+            ))
+        } else {
+            Box::new(tracing_subscriber::layer::SubscriberExt::with(
+                tracing_subscriber::registry(),
+                layer,
+            ))
+        };
+        tracing::subscriber::with_default(subscriber, || {
+            let url = format!("http://owm/data/3.0/onecall?lat=1&lon=2&appid={owm_key}");
+            tracing::info!("Querying {}", url);
+            tracing::error!(
+                "http_error: {:?}",
+                anyhow::anyhow!("error sending request for url ({url})")
+            );
+            tracing::warn!(client_key, "rejected client");
+            let span = tracing::info_span!("request", key = owm_key);
+            span.in_scope(|| tracing::info!("inside span"));
+        });
+    }
+
+    let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+    assert!(output.contains(REDACTED));
+    assert!(!output.contains(owm_key));
+    assert!(!output.contains(client_key));
+}
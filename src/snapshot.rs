@@ -0,0 +1,226 @@
+use std::{fs, path::Path};
+
+use anyhow::{anyhow, Context};
+use chrono::{TimeZone, Utc};
+use protobuf::Message;
+
+use crate::{
+    cache::{CacheValue, Cached, Hits, SpatialCache},
+    geocoding::entities::ReverseGeocode,
+    validation::normalize_location,
+    weather::entities::{CachedForecast, ProtoAdapter},
+    weather_proto::cache_snapshot::{CacheEntry, CacheSnapshot},
+    AppState,
+};
+
+/// How the value of a cache tier is stored in a snapshot entry.
+trait SnapshotValue: Sized {
+    fn write(&self, entry: &mut CacheEntry);
+    fn read(entry: CacheEntry) -> anyhow::Result<Self>;
+}
+
+impl SnapshotValue for CachedForecast {
+    fn write(&self, entry: &mut CacheEntry) {
+        entry.units = self.units.to_string();
+        entry.weather = Some(self.weather.clone()).into();
+        entry.daily_only = self.daily_only;
+    }
+
+    fn read(entry: CacheEntry) -> anyhow::Result<Self> {
+        Ok(CachedForecast {
+            units: entry.units.parse()?,
+            weather: entry.weather.unwrap_or_default(),
+            daily_only: entry.daily_only,
+        })
+    }
+}
+
+impl SnapshotValue for i64 {
+    fn write(&self, entry: &mut CacheEntry) {
+        entry.aqi = *self;
+    }
+
+    fn read(entry: CacheEntry) -> anyhow::Result<Self> {
+        Ok(entry.aqi)
+    }
+}
+
+impl SnapshotValue for ReverseGeocode {
+    fn write(&self, entry: &mut CacheEntry) {
+        entry.reverse_geocode = Some(self.to_proto()).into();
+        entry.local_names = self.local_names.clone();
+    }
+
+    fn read(entry: CacheEntry) -> anyhow::Result<Self> {
+        Ok(ReverseGeocode {
+            local_names: entry.local_names.clone(),
+            ..ReverseGeocode::from(entry.reverse_geocode.get_or_default())
+        })
+    }
+}
+
+impl<T: SnapshotValue> ProtoAdapter for Cached<T> {
+    type ProtoType = CacheEntry;
+
+    fn to_proto(&self) -> Self::ProtoType {
+        let mut entry = CacheEntry {
+            latitude: self.location.latitude,
+            longitude: self.location.longitude,
+            expiry: self.expiry.timestamp(),
+            fetched: self.fetched.timestamp(),
+            hits: self.hits.get(),
+            ..Default::default()
+        };
+        self.value.write(&mut entry);
+        entry
+    }
+}
+
+impl<T: SnapshotValue> TryFrom<CacheEntry> for Cached<T> {
+    type Error = anyhow::Error;
+
+    fn try_from(entry: CacheEntry) -> anyhow::Result<Self> {
+        let expiry = Utc
+            .timestamp_opt(entry.expiry, 0)
+            .single()
+            .context("entry has an invalid expiry")?;
+        let fetched = Utc
+            .timestamp_opt(entry.fetched, 0)
+            .single()
+            .context("entry has an invalid fetch time")?;
+        let location = normalize_location(entry.latitude, entry.longitude)
+            .map_err(|err| anyhow!("entry has invalid coordinates: {}", err))?;
+        Ok(Cached {
+            location,
+            fetched,
+            expiry,
+            hits: Hits::new(entry.hits),
+            value: T::read(entry)?,
+        })
+    }
+}
+
+fn tier_entries<T: CacheValue + SnapshotValue>(cache: &SpatialCache<T>) -> Vec<CacheEntry> {
+    cache
+        .entries()
+        .iter()
+        .map(|(_, entry)| entry.to_proto())
+        .collect()
+}
+
+fn decode_entries<T: SnapshotValue>(entries: Vec<CacheEntry>) -> anyhow::Result<Vec<Cached<T>>> {
+    entries.into_iter().map(Cached::try_from).collect()
+}
+
+/// Captures every entry of every tier, expired ones included since they can
+/// still be served while OWM is unavailable.
+pub(crate) fn take_snapshot(data: &AppState) -> CacheSnapshot {
+    CacheSnapshot {
+        created: Utc::now().timestamp(),
+        entries: tier_entries(&data.caches.weather),
+        aqi_entries: tier_entries(&data.caches.aqi),
+        reverse_geocode_entries: tier_entries(&data.caches.reverse_geocode),
+        ..Default::default()
+    }
+}
+
+/// Adds the entries of `snapshot` to their cache tiers, returning how many
+/// were imported. An imported entry replaces one with the same key at the same
+/// point, other entries are kept. Nothing is imported if any entry is invalid,
+/// e.g. has coordinates off the globe.
+pub(crate) fn restore_snapshot(data: &AppState, snapshot: CacheSnapshot) -> anyhow::Result<usize> {
+    let weather = decode_entries(snapshot.entries)?;
+    let aqi = decode_entries(snapshot.aqi_entries)?;
+    let reverse_geocode = decode_entries(snapshot.reverse_geocode_entries)?;
+    Ok(data.caches.weather.restore(weather)
+        + data.caches.aqi.restore(aqi)
+        + data.caches.reverse_geocode.restore(reverse_geocode))
+}
+
+pub(crate) fn read_snapshot(path: &Path) -> anyhow::Result<CacheSnapshot> {
+    let bytes = fs::read(path).with_context(|| format!("could not read {}", path.display()))?;
+    CacheSnapshot::parse_from_bytes(&bytes)
+        .with_context(|| format!("{} is not a cache snapshot", path.display()))
+}
+
+/// Writes a snapshot of the cache to `path`, replacing it atomically so that a
+/// crash mid-write never leaves a truncated snapshot behind.
+pub(crate) fn write_snapshot(data: &AppState, path: &Path) -> anyhow::Result<usize> {
+    let snapshot = take_snapshot(data);
+    let bytes = snapshot.write_to_bytes()?;
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, bytes)
+        .with_context(|| format!("could not write {}", tmp_path.display()))?;
+    fs::rename(&tmp_path, path).with_context(|| format!("could not replace {}", path.display()))?;
+    Ok(
+        snapshot.entries.len()
+            + snapshot.aqi_entries.len()
+            + snapshot.reverse_geocode_entries.len(),
+    )
+}
+
+#[test]
+fn test_cache_entry_round_trip() {
+    let entry = Cached {
+        value: CachedForecast {
+            weather: crate::weather_proto::weather_message::WeatherInfo {
+                aqi: String::from("Good"),
+                ..Default::default()
+            },
+            units: crate::weather::entities::Units::Imperial,
+            daily_only: true,
+        },
+        location: crate::entities::Location::at(37.54952, -121.94277),
+        fetched: Utc.timestamp_opt(1_699_999_100, 0).unwrap(),
+        expiry: Utc.timestamp_opt(1_700_000_000, 0).unwrap(),
+        hits: Hits::new(3),
+    };
+    let bytes = entry.to_proto().write_to_bytes().unwrap();
+    let restored =
+        Cached::<CachedForecast>::try_from(CacheEntry::parse_from_bytes(&bytes).unwrap()).unwrap();
+    assert_eq!(restored.value.weather, entry.value.weather);
+    assert_eq!(
+        restored.value.units,
+        crate::weather::entities::Units::Imperial
+    );
+    assert!(restored.value.daily_only);
+    assert_eq!(restored.fetched, entry.fetched);
+    assert_eq!(restored.expiry, entry.expiry);
+    assert_eq!(restored.hits.get(), 3);
+    assert_eq!(restored.location, entry.location);
+
+    let geocode = Cached {
+        value: ReverseGeocode {
+            name: String::from("Fremont"),
+            country: String::from("US"),
+            state: String::from("California"),
+            latitude: 37.55,
+            longitude: -121.98,
+            local_names: [(String::from("ja"), String::from("フリーモント"))].into(),
+        },
+        location: entry.location,
+        fetched: entry.fetched,
+        expiry: entry.expiry,
+        hits: Hits::default(),
+    };
+    let restored = Cached::<ReverseGeocode>::try_from(geocode.to_proto()).unwrap();
+    assert_eq!(restored.value.name, "Fremont");
+    assert_eq!(restored.value.local_names, geocode.value.local_names);
+    let aqi = Cached {
+        value: 4,
+        location: entry.location,
+        fetched: entry.fetched,
+        expiry: entry.expiry,
+        hits: Hits::default(),
+    };
+    assert_eq!(Cached::<i64>::try_from(aqi.to_proto()).unwrap().value, 4);
+
+    for (latitude, longitude) in [(f64::NAN, 0.0), (91.0, 0.0), (0.0, f64::INFINITY)] {
+        let entry = CacheEntry {
+            latitude,
+            longitude,
+            ..aqi.to_proto()
+        };
+        assert!(Cached::<i64>::try_from(entry).is_err());
+    }
+}
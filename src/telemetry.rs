@@ -0,0 +1,492 @@
+use std::{
+    future::{ready, Future, Ready},
+    pin::Pin,
+    time::Instant,
+};
+
+use actix_web::{
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::{HeaderName, HeaderValue},
+    Error, HttpMessage,
+};
+use rand::Rng;
+use serde::Deserialize;
+use tracing::{info, info_span, Instrument, Span};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+
+use crate::{
+    auth::ClientIdentity,
+    entities::CacheStatus,
+    redact::{RedactingMakeWriter, Redactor},
+};
+
+const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct LoggingConfig {
+    pub(crate) format: LogFormat,
+    // filter directives such as "info" or "info,rust_weather_api=debug".
+    // RUST_LOG takes precedence when set.
+    pub(crate) level: String,
+    // log one line per request with its status, latency, cache result and client.
+    pub(crate) access_log: bool,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            format: LogFormat::Text,
+            level: String::from("info"),
+            access_log: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct TelemetryConfig {
+    // OTLP/HTTP collector, e.g. http://localhost:4318/v1/traces. Spans are only
+    // exported when this is set and the binary is built with the `otel` feature.
+    pub(crate) otlp_endpoint: Option<String>,
+    pub(crate) service_name: String,
+    // fraction of traces started here that are sampled, between 0 and 1.
+    pub(crate) sample_ratio: f64,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            otlp_endpoint: None,
+            service_name: String::from("rust-weather-api"),
+            sample_ratio: 1.0,
+        }
+    }
+}
+
+/// Installs the global tracing subscriber, writing text or JSON logs and
+/// exporting spans over OTLP if configured.
+pub(crate) fn init(logging: &LoggingConfig, config: &TelemetryConfig) -> anyhow::Result<()> {
+    let filter = match EnvFilter::try_from_default_env() {
+        Ok(filter) => filter,
+        Err(_) => EnvFilter::try_new(&logging.level)?,
+    };
+    let json = logging.format == LogFormat::Json;
+    let registry = tracing_subscriber::registry()
+        .with(filter)
+        .with((!json).then(|| tracing_subscriber::fmt::layer().with_writer(redacting_stdout())))
+        .with(json.then(|| {
+            // the span list carries the request id into every line logged for a request.
+            tracing_subscriber::fmt::layer()
+                .with_writer(redacting_stdout())
+                .json()
+                .with_current_span(false)
+                .with_span_list(true)
+        }));
+
+    #[cfg(feature = "otel")]
+    {
+        let otel_layer = match &config.otlp_endpoint {
+            Some(endpoint) => Some(otel::layer(config, endpoint)?),
+            None => None,
+        };
+        registry.with(otel_layer).try_init()?;
+    }
+    #[cfg(not(feature = "otel"))]
+    {
+        registry.try_init()?;
+        if config.otlp_endpoint.is_some() {
+            tracing::warn!(
+                "telemetry.otlp_endpoint is set but this build lacks the `otel` feature"
+            );
+        }
+    }
+    Ok(())
+}
+
+fn redacting_stdout() -> RedactingMakeWriter<fn() -> std::io::Stdout> {
+    RedactingMakeWriter::new(std::io::stdout, Redactor::global())
+}
+
+/// Flushes spans that have not been exported yet.
+pub(crate) fn shutdown() {
+    #[cfg(feature = "otel")]
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+/// W3C trace-context headers for an outgoing request made in the current span.
+pub(crate) fn trace_headers() -> reqwest::header::HeaderMap {
+    #[allow(unused_mut)]
+    let mut headers = reqwest::header::HeaderMap::new();
+    #[cfg(feature = "otel")]
+    otel::inject(&Span::current(), &mut headers);
+    headers
+}
+
+/// Opens the root span of every request, continuing the caller's trace when
+/// the request carries a `traceparent` header. The span carries a request id,
+/// taken from `X-Request-Id` or generated, which is echoed on the response.
+pub(crate) struct RequestTracing {
+    pub(crate) access_log: bool,
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequestTracing
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequestTracingMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestTracingMiddleware {
+            service,
+            access_log: self.access_log,
+        }))
+    }
+}
+
+pub(crate) struct RequestTracingMiddleware<S> {
+    service: S,
+    access_log: bool,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestTracingMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let started = Instant::now();
+        let request_id = req
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|id| is_valid_request_id(id))
+            .map(String::from)
+            .unwrap_or_else(new_request_id);
+        let method = req.method().clone();
+        let path = req.path().to_owned();
+        let span = info_span!(
+            "http_request",
+            otel.kind = "server",
+            request_id = %request_id,
+            http.method = %method,
+            http.target = %path,
+            http.status_code = tracing::field::Empty,
+        );
+        #[cfg(feature = "otel")]
+        otel::set_parent(&span, req.headers());
+
+        let access_log = self.access_log;
+        let fut = span.in_scope(|| self.service.call(req));
+        Box::pin(
+            async move {
+                let mut result = fut.await;
+                let (status, cache, client) = match &mut result {
+                    Ok(res) => {
+                        if let Ok(value) = HeaderValue::from_str(&request_id) {
+                            res.headers_mut().insert(REQUEST_ID_HEADER, value);
+                        }
+                        let extensions = res.request().extensions();
+                        (
+                            res.status(),
+                            extensions.get::<CacheStatus>().map(CacheStatus::as_str),
+                            extensions
+                                .get::<ClientIdentity>()
+                                .map(|client| client.name.clone()),
+                        )
+                    }
+                    Err(err) => (err.as_response_error().status_code(), None, None),
+                };
+                Span::current().record("http.status_code", status.as_u16());
+                if access_log {
+                    info!(
+                        target: "access_log",
+                        method = %method,
+                        path = %path,
+                        status = status.as_u16(),
+                        latency_ms = started.elapsed().as_secs_f64() * 1000.0,
+                        cache = cache.unwrap_or("none"),
+                        client = client.as_deref().unwrap_or("anonymous"),
+                        "request completed"
+                    );
+                }
+                result
+            }
+            .instrument(span),
+        )
+    }
+}
+
+fn new_request_id() -> String {
+    format!("{:032x}", rand::thread_rng().gen::<u128>())
+}
+
+// a caller-supplied id is reused only if it is short and safe to log.
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 64
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_' || b == b'.')
+}
+
+#[cfg(feature = "otel")]
+mod otel {
+    use std::{borrow::Cow, future::Future, pin::Pin, sync::Arc};
+
+    use actix_web::http::header::HeaderMap;
+    use opentelemetry::{
+        global,
+        propagation::{Extractor, Injector},
+        sdk::{
+            export::trace::{ExportResult, SpanData, SpanExporter},
+            propagation::TraceContextPropagator,
+            trace::{self, EvictedHashMap, EvictedQueue, Sampler},
+            Resource,
+        },
+        trace::{Status, TracerProvider},
+        Array, KeyValue, StringValue, Value,
+    };
+    use opentelemetry_otlp::WithExportConfig;
+    use tracing::Span;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+    use super::TelemetryConfig;
+    use crate::redact::Redactor;
+
+    pub(super) fn layer<S>(
+        config: &TelemetryConfig,
+        endpoint: &str,
+    ) -> anyhow::Result<tracing_opentelemetry::OpenTelemetryLayer<S, trace::Tracer>>
+    where
+        S: tracing::Subscriber + for<'span> tracing_subscriber::registry::LookupSpan<'span>,
+    {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let exporter = opentelemetry_otlp::SpanExporterBuilder::from(
+            opentelemetry_otlp::new_exporter()
+                .http()
+                .with_endpoint(endpoint),
+        )
+        .build_span_exporter()?;
+        let provider = trace::TracerProvider::builder()
+            .with_batch_exporter(
+                RedactingExporter {
+                    inner: exporter,
+                    redactor: Redactor::global(),
+                },
+                // actix runs every worker on a current-thread runtime.
+                opentelemetry::runtime::TokioCurrentThread,
+            )
+            .with_config(
+                trace::config()
+                    .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+                        config.sample_ratio,
+                    ))))
+                    .with_resource(Resource::new(vec![KeyValue::new(
+                        "service.name",
+                        config.service_name.clone(),
+                    )])),
+            )
+            .build();
+        let tracer = provider.tracer("rust-weather-api");
+        global::set_tracer_provider(provider);
+        Ok(tracing_opentelemetry::layer().with_tracer(tracer))
+    }
+
+    /// Scrubs secrets out of spans before they are exported, as the log writer
+    /// does for log lines: error events and fields can carry OWM URLs and keys.
+    #[derive(Debug)]
+    struct RedactingExporter<E> {
+        inner: E,
+        redactor: Arc<Redactor>,
+    }
+
+    impl<E: SpanExporter> SpanExporter for RedactingExporter<E> {
+        fn export(
+            &mut self,
+            mut batch: Vec<SpanData>,
+        ) -> Pin<Box<dyn Future<Output = ExportResult> + Send>> {
+            for span in &mut batch {
+                redact_span(&self.redactor, span);
+            }
+            self.inner.export(batch)
+        }
+
+        fn shutdown(&mut self) {
+            self.inner.shutdown()
+        }
+
+        fn force_flush(&mut self) -> Pin<Box<dyn Future<Output = ExportResult> + Send>> {
+            self.inner.force_flush()
+        }
+    }
+
+    // the span name, attributes, events and error status. The rebuilt
+    // attribute map and event queue hold what the span kept, so nothing more
+    // is dropped, but the counts of what was dropped before are lost.
+    pub(super) fn redact_span(redactor: &Redactor, span: &mut SpanData) {
+        span.name = redact_cow(redactor, std::mem::take(&mut span.name));
+        let mut attributes = EvictedHashMap::new(u32::MAX, span.attributes.len());
+        for (key, value) in span.attributes.iter() {
+            attributes.insert(KeyValue::new(key.clone(), redact_value(redactor, value)));
+        }
+        span.attributes = attributes;
+        let mut events = EvictedQueue::new(u32::MAX);
+        events.extend(
+            std::mem::replace(&mut span.events, EvictedQueue::new(0))
+                .into_iter()
+                .map(|mut event| {
+                    event.name = redact_cow(redactor, std::mem::take(&mut event.name));
+                    for attribute in &mut event.attributes {
+                        attribute.value = redact_value(redactor, &attribute.value);
+                    }
+                    event
+                }),
+        );
+        span.events = events;
+        if let Status::Error { description } = &mut span.status {
+            *description = redact_cow(redactor, std::mem::take(description));
+        }
+    }
+
+    fn redact_cow(redactor: &Redactor, text: Cow<'static, str>) -> Cow<'static, str> {
+        let redacted = match redactor.redact(&text) {
+            Cow::Owned(redacted) => Some(redacted),
+            Cow::Borrowed(_) => None,
+        };
+        redacted.map_or(text, Cow::Owned)
+    }
+
+    fn redact_value(redactor: &Redactor, value: &Value) -> Value {
+        let redact_string = |text: &StringValue| -> StringValue {
+            redactor.redact(text.as_str()).into_owned().into()
+        };
+        match value {
+            Value::String(text) => Value::String(redact_string(text)),
+            Value::Array(Array::String(texts)) => {
+                Value::Array(Array::String(texts.iter().map(redact_string).collect()))
+            }
+            other => other.clone(),
+        }
+    }
+
+    struct HeaderExtractor<'a>(&'a HeaderMap);
+
+    impl Extractor for HeaderExtractor<'_> {
+        fn get(&self, key: &str) -> Option<&str> {
+            self.0.get(key).and_then(|value| value.to_str().ok())
+        }
+
+        fn keys(&self) -> Vec<&str> {
+            self.0.keys().map(|name| name.as_str()).collect()
+        }
+    }
+
+    struct HeaderInjector<'a>(&'a mut reqwest::header::HeaderMap);
+
+    impl Injector for HeaderInjector<'_> {
+        fn set(&mut self, key: &str, value: String) {
+            if let (Ok(name), Ok(value)) = (
+                reqwest::header::HeaderName::from_bytes(key.as_bytes()),
+                reqwest::header::HeaderValue::from_str(&value),
+            ) {
+                self.0.insert(name, value);
+            }
+        }
+    }
+
+    pub(super) fn set_parent(span: &Span, headers: &HeaderMap) {
+        let parent = global::get_text_map_propagator(|propagator| {
+            propagator.extract(&HeaderExtractor(headers))
+        });
+        span.set_parent(parent);
+    }
+
+    pub(super) fn inject(span: &Span, headers: &mut reqwest::header::HeaderMap) {
+        let context = span.context();
+        global::get_text_map_propagator(|propagator| {
+            propagator.inject_context(&context, &mut HeaderInjector(headers))
+        });
+    }
+}
+
+#[cfg(feature = "otel")]
+#[test]
+fn test_exported_spans_never_contain_keys() {
+    use std::{borrow::Cow, time::SystemTime};
+
+    use opentelemetry::{
+        sdk::{
+            export::trace::SpanData,
+            trace::{EvictedHashMap, EvictedQueue},
+            InstrumentationLibrary, Resource,
+        },
+        trace::{Event, SpanContext, SpanId, SpanKind, Status},
+        KeyValue,
+    };
+
+    let owm_key = "0123456789abcdef0123456789abcdef";
+    let redactor = Redactor::default();
+    redactor.add_secrets([owm_key.to_string()]);
+    let url = format!("http://owm/data/3.0/onecall?lat=1&lon=2&appid={owm_key}");
+    let mut attributes = EvictedHashMap::new(128, 2);
+    attributes.insert(KeyValue::new("http.url", url.clone()));
+    attributes.insert(KeyValue::new("key", owm_key));
+    let mut events = EvictedQueue::new(128);
+    events.extend([Event::new(
+        format!("error sending request for url ({url})"),
+        SystemTime::now(),
+        vec![KeyValue::new("error", url.clone())],
+        0,
+    )]);
+    let mut span = SpanData {
+        span_context: SpanContext::empty_context(),
+        parent_span_id: SpanId::INVALID,
+        span_kind: SpanKind::Client,
+        name: Cow::Borrowed("owm_request"),
+        start_time: SystemTime::now(),
+        end_time: SystemTime::now(),
+        attributes,
+        events,
+        links: EvictedQueue::new(0),
+        status: Status::error(url),
+        resource: Cow::Owned(Resource::empty()),
+        instrumentation_lib: InstrumentationLibrary::default(),
+    };
+
+    otel::redact_span(&redactor, &mut span);
+    let exported = format!("{span:?}");
+    assert!(exported.contains(crate::redact::REDACTED));
+    assert!(!exported.contains(owm_key));
+}
+
+#[test]
+fn test_request_id_validation() {
+    assert!(is_valid_request_id("4bf92f3577b34da6-a3ce_929d.0e0e4736"));
+    assert!(is_valid_request_id(&new_request_id()));
+    assert!(!is_valid_request_id(""));
+    assert!(!is_valid_request_id("id with spaces"));
+    assert!(!is_valid_request_id("line\nbreak"));
+    assert!(!is_valid_request_id(&"a".repeat(65)));
+}
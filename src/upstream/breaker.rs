@@ -0,0 +1,238 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tracing::warn;
+
+use super::Endpoint;
+
+#[derive(Debug, Clone)]
+pub(crate) struct BreakerConfig {
+    // consecutive failures before the circuit opens.
+    pub(crate) failure_threshold: u32,
+    // how long the circuit stays open before letting a probe through.
+    pub(crate) open_duration: Duration,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            open_duration: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum BreakerState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+#[derive(Debug)]
+struct BreakerInner {
+    state: BreakerState,
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+    // set while the single half-open probe is in flight.
+    probing: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct BreakerStatus {
+    pub(crate) state: BreakerState,
+    pub(crate) consecutive_failures: u32,
+    pub(crate) open_for_secs: Option<u64>,
+}
+
+#[derive(Debug)]
+pub(crate) struct CircuitBreaker {
+    config: BreakerConfig,
+    inner: Mutex<BreakerInner>,
+}
+
+impl CircuitBreaker {
+    pub(crate) fn new(config: BreakerConfig) -> Self {
+        Self {
+            config,
+            inner: Mutex::new(BreakerInner {
+                state: BreakerState::Closed,
+                consecutive_failures: 0,
+                opened_at: None,
+                probing: false,
+            }),
+        }
+    }
+
+    /// Returns a permit if a request may be sent. Once the open period has
+    /// elapsed exactly one caller is let through to probe the upstream.
+    pub(crate) fn try_acquire(&self) -> Option<Permit<'_>> {
+        let mut inner = self.inner.lock().unwrap();
+        let probe = match inner.state {
+            BreakerState::Closed => false,
+            BreakerState::Open => {
+                let elapsed = inner.opened_at.map_or(Duration::MAX, |at| at.elapsed());
+                if elapsed < self.config.open_duration {
+                    return None;
+                }
+                inner.state = BreakerState::HalfOpen;
+                true
+            }
+            BreakerState::HalfOpen => {
+                if inner.probing {
+                    return None;
+                }
+                true
+            }
+        };
+        inner.probing |= probe;
+        Some(Permit {
+            breaker: self,
+            probe,
+            recorded: false,
+        })
+    }
+
+    // lets the next probe through after one ended without an outcome.
+    fn release(&self) {
+        self.inner.lock().unwrap().probing = false;
+    }
+
+    fn record_success(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.state = BreakerState::Closed;
+        inner.consecutive_failures = 0;
+        inner.opened_at = None;
+        inner.probing = false;
+    }
+
+    fn record_failure(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.consecutive_failures += 1;
+        inner.probing = false;
+        if inner.state == BreakerState::HalfOpen
+            || inner.consecutive_failures >= self.config.failure_threshold
+        {
+            if inner.state != BreakerState::Open {
+                warn!(
+                    "Opening circuit after {} consecutive failures",
+                    inner.consecutive_failures
+                );
+            }
+            inner.state = BreakerState::Open;
+            inner.opened_at = Some(Instant::now());
+        }
+    }
+
+    pub(crate) fn status(&self) -> BreakerStatus {
+        let inner = self.inner.lock().unwrap();
+        BreakerStatus {
+            state: inner.state,
+            consecutive_failures: inner.consecutive_failures,
+            open_for_secs: inner.opened_at.map(|at| at.elapsed().as_secs()),
+        }
+    }
+}
+
+/// A request let through by `CircuitBreaker::try_acquire`. Dropping it without
+/// recording an outcome, e.g. when nothing was sent or the request was
+/// cancelled, gives back the half-open probe.
+#[derive(Debug)]
+#[must_use]
+pub(crate) struct Permit<'a> {
+    breaker: &'a CircuitBreaker,
+    // whether this is the single half-open probe.
+    probe: bool,
+    recorded: bool,
+}
+
+impl Permit<'_> {
+    pub(crate) fn record_success(mut self) {
+        self.recorded = true;
+        self.breaker.record_success();
+    }
+
+    pub(crate) fn record_failure(mut self) {
+        self.recorded = true;
+        self.breaker.record_failure();
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        if self.probe && !self.recorded {
+            self.breaker.release();
+        }
+    }
+}
+
+/// One circuit breaker per upstream endpoint.
+#[derive(Debug)]
+pub(crate) struct Breakers {
+    breakers: HashMap<Endpoint, CircuitBreaker>,
+}
+
+impl Breakers {
+    pub(crate) fn new(config: BreakerConfig) -> Self {
+        Self {
+            breakers: Endpoint::ALL
+                .iter()
+                .map(|endpoint| (*endpoint, CircuitBreaker::new(config.clone())))
+                .collect(),
+        }
+    }
+
+    pub(crate) fn get(&self, endpoint: Endpoint) -> &CircuitBreaker {
+        &self.breakers[&endpoint]
+    }
+
+    pub(crate) fn statuses(&self) -> HashMap<Endpoint, BreakerStatus> {
+        self.breakers
+            .iter()
+            .map(|(endpoint, breaker)| (*endpoint, breaker.status()))
+            .collect()
+    }
+}
+
+#[test]
+fn test_breaker_opens_and_half_opens() {
+    let breaker = CircuitBreaker::new(BreakerConfig {
+        failure_threshold: 2,
+        open_duration: Duration::ZERO,
+    });
+    breaker.try_acquire().unwrap().record_failure();
+    assert_eq!(breaker.status().state, BreakerState::Closed);
+    breaker.try_acquire().unwrap().record_failure();
+    assert_eq!(breaker.status().state, BreakerState::Open);
+
+    // open duration has elapsed, so a single probe is let through.
+    let probe = breaker.try_acquire().unwrap();
+    assert_eq!(breaker.status().state, BreakerState::HalfOpen);
+    assert!(breaker.try_acquire().is_none());
+
+    // a failed probe re-opens the circuit immediately.
+    probe.record_failure();
+    assert_eq!(breaker.status().state, BreakerState::Open);
+
+    // a probe dropped mid-request lets the next one through.
+    drop(breaker.try_acquire().unwrap());
+    assert_eq!(breaker.status().state, BreakerState::HalfOpen);
+    breaker.try_acquire().unwrap().record_success();
+    assert_eq!(breaker.status().state, BreakerState::Closed);
+    assert!(breaker.try_acquire().is_some());
+}
+
+#[test]
+fn test_breaker_stays_open() {
+    let breaker = CircuitBreaker::new(BreakerConfig {
+        failure_threshold: 1,
+        open_duration: Duration::from_secs(60),
+    });
+    breaker.try_acquire().unwrap().record_failure();
+    assert!(breaker.try_acquire().is_none());
+}
@@ -0,0 +1,217 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use chrono::{DateTime, Duration, DurationRound, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::{Endpoint, UpstreamError};
+
+// days of per-day call counts kept for the usage endpoint.
+const HISTORY_DAYS: usize = 7;
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct BudgetLimits {
+    pub(crate) daily: Option<u64>,
+    pub(crate) per_minute: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct BudgetConfig {
+    pub(crate) limits: HashMap<Endpoint, BudgetLimits>,
+}
+
+impl Default for BudgetConfig {
+    // One Call 3.0 includes 1000 calls a day; every endpoint is limited to 60 calls a minute.
+    fn default() -> Self {
+        Self {
+            limits: Endpoint::ALL
+                .iter()
+                .map(|endpoint| {
+                    let daily = (*endpoint == Endpoint::OneCall).then_some(1000);
+                    (
+                        *endpoint,
+                        BudgetLimits {
+                            daily,
+                            per_minute: Some(60),
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counter {
+    day: Option<NaiveDate>,
+    daily_calls: u64,
+    minute: Option<DateTime<Utc>>,
+    minute_calls: u64,
+    // call counts of previous days, most recent first.
+    history: Vec<(NaiveDate, u64)>,
+}
+
+impl Counter {
+    fn roll(&mut self, now: DateTime<Utc>) {
+        let today = now.date_naive();
+        if self.day != Some(today) {
+            if let Some(day) = self.day {
+                self.history.insert(0, (day, self.daily_calls));
+                self.history.truncate(HISTORY_DAYS);
+            }
+            self.day = Some(today);
+            self.daily_calls = 0;
+        }
+        let minute = now.duration_trunc(Duration::minutes(1)).unwrap();
+        if self.minute != Some(minute) {
+            self.minute = Some(minute);
+            self.minute_calls = 0;
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct EndpointUsage {
+    pub(crate) date: Option<NaiveDate>,
+    pub(crate) calls_today: u64,
+    pub(crate) daily_limit: Option<u64>,
+    pub(crate) calls_this_minute: u64,
+    pub(crate) per_minute_limit: Option<u64>,
+    pub(crate) history: Vec<(NaiveDate, u64)>,
+}
+
+/// Counts upstream calls per endpoint and refuses calls over the configured budget.
+#[derive(Debug)]
+pub(crate) struct Budget {
+    config: BudgetConfig,
+    counters: Mutex<HashMap<Endpoint, Counter>>,
+}
+
+impl Budget {
+    pub(crate) fn new(config: BudgetConfig) -> Self {
+        Self {
+            config,
+            counters: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records one call to `endpoint`, or fails with `UpstreamError::BudgetExhausted`
+    /// if the call would exceed the daily or per-minute limit.
+    pub(crate) fn try_acquire(&self, endpoint: Endpoint) -> Result<(), UpstreamError> {
+        self.try_acquire_at(endpoint, Utc::now())
+    }
+
+    fn try_acquire_at(&self, endpoint: Endpoint, now: DateTime<Utc>) -> Result<(), UpstreamError> {
+        let limits = self.limits(endpoint);
+        let mut counters = self.counters.lock().unwrap();
+        let counter = counters.entry(endpoint).or_default();
+        counter.roll(now);
+
+        if limits
+            .daily
+            .is_some_and(|limit| counter.daily_calls >= limit)
+        {
+            let tomorrow = now.date_naive().succ_opt().unwrap();
+            let reset = tomorrow.and_hms_opt(0, 0, 0).unwrap().and_utc();
+            return Err(UpstreamError::BudgetExhausted {
+                endpoint,
+                retry_after_secs: (reset - now).num_seconds().max(1) as u64,
+            });
+        }
+        if limits
+            .per_minute
+            .is_some_and(|limit| counter.minute_calls >= limit)
+        {
+            let reset = counter.minute.unwrap() + Duration::minutes(1);
+            return Err(UpstreamError::BudgetExhausted {
+                endpoint,
+                retry_after_secs: (reset - now).num_seconds().max(1) as u64,
+            });
+        }
+
+        counter.daily_calls += 1;
+        counter.minute_calls += 1;
+        Ok(())
+    }
+
+    fn limits(&self, endpoint: Endpoint) -> BudgetLimits {
+        self.config
+            .limits
+            .get(&endpoint)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn usage(&self) -> HashMap<Endpoint, EndpointUsage> {
+        let now = Utc::now();
+        let mut counters = self.counters.lock().unwrap();
+        Endpoint::ALL
+            .iter()
+            .map(|endpoint| {
+                let limits = self.limits(*endpoint);
+                let counter = counters.entry(*endpoint).or_default();
+                counter.roll(now);
+                let usage = EndpointUsage {
+                    date: counter.day,
+                    calls_today: counter.daily_calls,
+                    daily_limit: limits.daily,
+                    calls_this_minute: counter.minute_calls,
+                    per_minute_limit: limits.per_minute,
+                    history: counter.history.clone(),
+                };
+                (*endpoint, usage)
+            })
+            .collect()
+    }
+}
+
+#[test]
+fn test_budget_per_minute_limit() {
+    let budget = Budget::new(BudgetConfig {
+        limits: HashMap::from([(
+            Endpoint::OneCall,
+            BudgetLimits {
+                daily: Some(10),
+                per_minute: Some(2),
+            },
+        )]),
+    });
+    let now = DateTime::parse_from_rfc3339("2023-07-01T12:00:30Z")
+        .unwrap()
+        .with_timezone(&Utc);
+    assert!(budget.try_acquire_at(Endpoint::OneCall, now).is_ok());
+    assert!(budget.try_acquire_at(Endpoint::OneCall, now).is_ok());
+    match budget.try_acquire_at(Endpoint::OneCall, now) {
+        Err(UpstreamError::BudgetExhausted {
+            retry_after_secs, ..
+        }) => assert_eq!(retry_after_secs, 30),
+        other => panic!("expected exhausted budget, got {:?}", other),
+    }
+    // other endpoints are not limited.
+    assert!(budget.try_acquire_at(Endpoint::Geo, now).is_ok());
+    // the next minute starts a new window.
+    assert!(budget
+        .try_acquire_at(Endpoint::OneCall, now + Duration::minutes(1))
+        .is_ok());
+}
+
+#[test]
+fn test_budget_daily_limit_rolls_over() {
+    let budget = Budget::new(BudgetConfig {
+        limits: HashMap::from([(
+            Endpoint::OneCall,
+            BudgetLimits {
+                daily: Some(1),
+                per_minute: None,
+            },
+        )]),
+    });
+    let now = DateTime::parse_from_rfc3339("2023-07-01T23:59:00Z")
+        .unwrap()
+        .with_timezone(&Utc);
+    assert!(budget.try_acquire_at(Endpoint::OneCall, now).is_ok());
+    assert!(budget.try_acquire_at(Endpoint::OneCall, now).is_err());
+    assert!(budget
+        .try_acquire_at(Endpoint::OneCall, now + Duration::minutes(2))
+        .is_ok());
+}
@@ -0,0 +1,341 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use reqwest::{header::RETRY_AFTER, Response, StatusCode};
+use serde::Serialize;
+use tracing::debug;
+
+use crate::{metrics::Metrics, telemetry};
+
+use super::{
+    breaker::{BreakerConfig, Breakers},
+    budget::{Budget, BudgetConfig},
+    keys::{APIKey, KeyRing},
+    Endpoint, UpstreamError,
+};
+
+#[derive(Debug, Clone)]
+pub(crate) struct RetryPolicy {
+    // number of retries after the first attempt.
+    pub(crate) max_retries: u32,
+    pub(crate) base_delay: Duration,
+    // upper bound for a single backoff; a longer Retry-After is not waited on.
+    pub(crate) max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            base_delay: Duration::from_millis(200),
+            max_delay: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RetryPolicy {
+    // exponential backoff with full jitter: a random delay in [0, base * 2^attempt].
+    fn backoff(&self, attempt: u32) -> Duration {
+        let ceiling = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay);
+        let millis = ceiling.as_millis() as u64;
+        Duration::from_millis(rand::thread_rng().gen_range(0..=millis))
+    }
+}
+
+/// The outcome of the most recent call to an endpoint.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct LastCall {
+    pub(crate) at: DateTime<Utc>,
+    // None if no response arrived.
+    pub(crate) status: Option<u16>,
+    pub(crate) ok: bool,
+}
+
+/// A single pooled HTTP client shared by every upstream call.
+#[derive(Debug, Clone)]
+pub(crate) struct UpstreamClient {
+    client: reqwest::Client,
+    retry: RetryPolicy,
+    pub(crate) breakers: Arc<Breakers>,
+    pub(crate) budget: Arc<Budget>,
+    pub(crate) keys: Arc<KeyRing>,
+    metrics: Arc<Metrics>,
+    last_calls: Arc<Mutex<HashMap<Endpoint, LastCall>>>,
+}
+
+impl UpstreamClient {
+    pub(crate) fn new(
+        connect_timeout: Duration,
+        request_timeout: Duration,
+        retry: RetryPolicy,
+        breaker: BreakerConfig,
+        budget: BudgetConfig,
+        keys: Arc<KeyRing>,
+        metrics: Arc<Metrics>,
+    ) -> anyhow::Result<Self> {
+        let client = reqwest::Client::builder()
+            .connect_timeout(connect_timeout)
+            .timeout(request_timeout)
+            .build()?;
+        Ok(Self {
+            client,
+            retry,
+            breakers: Arc::new(Breakers::new(breaker)),
+            budget: Arc::new(Budget::new(budget)),
+            keys,
+            metrics,
+            last_calls: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    /// Issues a GET request to `endpoint` with the URL built for a key from the
+    /// key ring. A key that is rejected or over its quota (401/429) is set aside
+    /// and the request is repeated with the next usable key.
+    pub(crate) async fn get(
+        &self,
+        endpoint: Endpoint,
+        url_for_key: impl Fn(&APIKey) -> String,
+    ) -> anyhow::Result<Response> {
+        let mut last_response = None;
+        for _ in 0..self.keys.len() {
+            let Some(key) = self.keys.pick() else {
+                self.metrics
+                    .record_upstream_rejected(endpoint, "no_usable_key");
+                break;
+            };
+            let response = self.get_url(endpoint, &url_for_key(&key)).await?;
+            if !is_key_rejected(response.status()) {
+                return Ok(response);
+            }
+            self.keys.mark_exhausted(&key, retry_after(&response));
+            last_response = Some(response);
+        }
+        last_response.ok_or_else(|| UpstreamError::NoUsableKey.into())
+    }
+
+    /// Issues a GET request to `endpoint`, short-circuiting with
+    /// `UpstreamError::CircuitOpen` while its breaker is open and with
+    /// `UpstreamError::BudgetExhausted` once its call budget is spent. The outcome
+    /// after retries is reported to the breaker as a single success or failure.
+    #[tracing::instrument(
+        skip(self, url),
+        fields(otel.kind = "client", http.status_code = tracing::field::Empty)
+    )]
+    async fn get_url(&self, endpoint: Endpoint, url: &str) -> anyhow::Result<Response> {
+        // given back if this future is dropped first, e.g. on a client disconnect.
+        let Some(permit) = self.breakers.get(endpoint).try_acquire() else {
+            self.metrics
+                .record_upstream_rejected(endpoint, "circuit_open");
+            return Err(UpstreamError::CircuitOpen(endpoint).into());
+        };
+        if let Err(err) = self.budget.try_acquire(endpoint) {
+            // nothing was sent, so the breaker neither failed nor recovered.
+            drop(permit);
+            self.metrics
+                .record_upstream_rejected(endpoint, "budget_exhausted");
+            return Err(err.into());
+        }
+        let result = self.get_with_retries(endpoint, url).await;
+        let status = result.as_ref().ok().map(|response| response.status());
+        // a 429 means OWM is up but throttling us, which the key ring and budget handle.
+        let ok = status.is_some_and(|status| !status.is_server_error());
+        if let Some(status) = status {
+            tracing::Span::current().record("http.status_code", status.as_u16());
+        }
+        if ok {
+            permit.record_success();
+        } else {
+            permit.record_failure();
+        }
+        self.last_calls.lock().unwrap().insert(
+            endpoint,
+            LastCall {
+                at: Utc::now(),
+                status: status.map(|status| status.as_u16()),
+                ok,
+            },
+        );
+        result
+    }
+
+    pub(crate) fn last_calls(&self) -> HashMap<Endpoint, LastCall> {
+        self.last_calls.lock().unwrap().clone()
+    }
+
+    /// Issues a GET request, retrying transient failures (5xx, timeouts and
+    /// connection errors) with backoff. Every retry is charged to the budget. The
+    /// last response is returned as is once retries or budget are exhausted, so
+    /// callers still get to inspect the status code. A 429 is returned at once
+    /// while another key is usable, since `get` can move on to that key; with
+    /// none left it is retried after its Retry-After like a 5xx.
+    async fn get_with_retries(&self, endpoint: Endpoint, url: &str) -> anyhow::Result<Response> {
+        let mut attempt = 0;
+        loop {
+            let started = Instant::now();
+            let result = self
+                .client
+                .get(url)
+                .headers(telemetry::trace_headers())
+                .send()
+                .await;
+            self.metrics.record_upstream(
+                endpoint,
+                result
+                    .as_ref()
+                    .ok()
+                    .map(|response| response.status().as_u16()),
+                started,
+            );
+            let delay = match result {
+                Ok(response) => {
+                    let other_keys = self.keys.usable_count() > 1;
+                    if !is_retryable(response.status(), other_keys)
+                        || attempt >= self.retry.max_retries
+                    {
+                        return Ok(response);
+                    }
+                    let delay = match retry_after(&response) {
+                        // the server asked us to back off for longer than we are willing to wait.
+                        Some(delay) if delay > self.retry.max_delay => return Ok(response),
+                        Some(delay) => delay,
+                        None => self.retry.backoff(attempt),
+                    };
+                    if self.budget.try_acquire(endpoint).is_err() {
+                        return Ok(response);
+                    }
+                    delay
+                }
+                Err(err) => {
+                    if !(err.is_timeout() || err.is_connect())
+                        || attempt >= self.retry.max_retries
+                        || self.budget.try_acquire(endpoint).is_err()
+                    {
+                        // reqwest errors carry the URL, and with it the OWM key.
+                        return Err(err.without_url().into());
+                    }
+                    self.retry.backoff(attempt)
+                }
+            };
+            attempt += 1;
+            debug!(
+                "Retrying upstream request (attempt {}) in {:?}",
+                attempt, delay
+            );
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+// a throttled key is only worth waiting on when there is no other key to use.
+fn is_retryable(status: StatusCode, other_keys: bool) -> bool {
+    status.is_server_error() || (status == StatusCode::TOO_MANY_REQUESTS && !other_keys)
+}
+
+fn is_key_rejected(status: StatusCode) -> bool {
+    status == StatusCode::UNAUTHORIZED || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?;
+    parse_retry_after(value, Utc::now())
+}
+
+// Retry-After is either a number of seconds or an HTTP date.
+fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    if let Ok(seconds) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = DateTime::parse_from_rfc2822(value.trim()).ok()?;
+    Some(
+        (date.with_timezone(&Utc) - now)
+            .to_std()
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+#[test]
+fn test_parse_retry_after() {
+    let now = DateTime::parse_from_rfc2822("Wed, 21 Oct 2015 07:28:00 GMT")
+        .unwrap()
+        .with_timezone(&Utc);
+    assert_eq!(
+        parse_retry_after("120", now),
+        Some(Duration::from_secs(120))
+    );
+    assert_eq!(
+        parse_retry_after("Wed, 21 Oct 2015 07:28:30 GMT", now),
+        Some(Duration::from_secs(30))
+    );
+    assert_eq!(
+        parse_retry_after("Wed, 21 Oct 2015 07:27:00 GMT", now),
+        Some(Duration::ZERO)
+    );
+    assert_eq!(parse_retry_after("soon", now), None);
+}
+
+#[test]
+fn test_throttled_keys_rotate_instead_of_retrying() {
+    assert!(!is_retryable(StatusCode::TOO_MANY_REQUESTS, true));
+    assert!(is_retryable(StatusCode::TOO_MANY_REQUESTS, false));
+    assert!(is_key_rejected(StatusCode::TOO_MANY_REQUESTS));
+    assert!(is_retryable(StatusCode::BAD_GATEWAY, true));
+    assert!(!is_key_rejected(StatusCode::BAD_GATEWAY));
+    assert!(!is_retryable(StatusCode::UNAUTHORIZED, false));
+}
+
+#[test]
+fn test_backoff_is_capped() {
+    let policy = RetryPolicy::default();
+    for attempt in 0..10 {
+        assert!(policy.backoff(attempt) <= policy.max_delay);
+    }
+}
+
+#[actix_web::test]
+async fn test_throttled_last_key_is_retried() {
+    let client = |keys: Vec<&str>| {
+        UpstreamClient::new(
+            Duration::from_secs(5),
+            Duration::from_secs(5),
+            RetryPolicy::default(),
+            BreakerConfig::default(),
+            BudgetConfig::default(),
+            Arc::new(
+                KeyRing::new(
+                    keys.into_iter().map(String::from).collect(),
+                    super::keys::KeyStrategy::Failover,
+                    Duration::from_secs(60),
+                )
+                .unwrap(),
+            ),
+            Arc::new(Metrics::new().unwrap()),
+        )
+        .unwrap()
+    };
+    let base_url = crate::fake_owm(vec!["429 Too Many Requests\r\nretry-after: 0", "200 OK"]).await;
+    let single = client(vec!["only-key"]);
+    let response = single
+        .get(Endpoint::OneCall, |_| base_url.clone())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(single.keys.usable_count(), 1);
+
+    // with another key to use, the throttled one is set aside instead.
+    let base_url = crate::fake_owm(vec!["429 Too Many Requests", "200 OK"]).await;
+    let pair = client(vec!["first-key", "second-key"]);
+    let response = pair
+        .get(Endpoint::OneCall, |_| base_url.clone())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(pair.keys.usable_count(), 1);
+}
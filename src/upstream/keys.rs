@@ -0,0 +1,315 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::redact::{Redactor, MIN_SECRET_LEN};
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct APIKey {
+    pub(crate) owm_key: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum KeyStrategy {
+    // spread calls evenly over every usable key.
+    #[default]
+    RoundRobin,
+    // use the first usable key, moving on only once it is exhausted.
+    Failover,
+}
+
+#[derive(Debug)]
+struct KeySlot {
+    key: APIKey,
+    exhausted_until: Option<Instant>,
+}
+
+impl KeySlot {
+    fn usable(&self, now: Instant) -> bool {
+        self.exhausted_until.is_none_or(|until| until <= now)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct KeyStatus {
+    // only the last few characters, enough to tell keys apart.
+    pub(crate) key: String,
+    pub(crate) exhausted_for_secs: Option<u64>,
+}
+
+/// The OWM keys the service may use, with the ones that recently hit their
+/// quota set aside for a cooldown.
+#[derive(Debug)]
+pub(crate) struct KeyRing {
+    strategy: KeyStrategy,
+    cooldown: Duration,
+    slots: Mutex<Vec<KeySlot>>,
+    next: AtomicUsize,
+}
+
+impl KeyRing {
+    pub(crate) fn new(
+        keys: Vec<String>,
+        strategy: KeyStrategy,
+        cooldown: Duration,
+    ) -> anyhow::Result<Self> {
+        let ring = Self {
+            strategy,
+            cooldown,
+            slots: Mutex::new(vec![]),
+            next: AtomicUsize::new(0),
+        };
+        ring.replace(keys)?;
+        Ok(ring)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.slots.lock().unwrap().len()
+    }
+
+    pub(crate) fn usable_count(&self) -> usize {
+        let now = Instant::now();
+        let slots = self.slots.lock().unwrap();
+        slots.iter().filter(|slot| slot.usable(now)).count()
+    }
+
+    /// Returns the key to use for the next call, or None if every key is exhausted.
+    pub(crate) fn pick(&self) -> Option<APIKey> {
+        let slots = self.slots.lock().unwrap();
+        let now = Instant::now();
+        let start = match self.strategy {
+            KeyStrategy::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed),
+            KeyStrategy::Failover => 0,
+        };
+        (0..slots.len())
+            .map(|offset| &slots[(start + offset) % slots.len()])
+            .find(|slot| slot.usable(now))
+            .map(|slot| slot.key.clone())
+    }
+
+    /// Sets `key` aside until `retry_after`, or the configured cooldown if OWM
+    /// did not say how long to wait.
+    pub(crate) fn mark_exhausted(&self, key: &APIKey, retry_after: Option<Duration>) {
+        let mut slots = self.slots.lock().unwrap();
+        if let Some(slot) = slots.iter_mut().find(|slot| &slot.key == key) {
+            let cooldown = retry_after.unwrap_or(self.cooldown);
+            warn!(
+                "OWM key {} is exhausted, setting it aside for {:?}",
+                mask(&key.owm_key),
+                cooldown
+            );
+            slot.exhausted_until = Some(Instant::now() + cooldown);
+        }
+    }
+
+    /// Swaps in a new set of keys, keeping the cooldown of keys that remain.
+    pub(crate) fn replace(&self, keys: Vec<String>) -> anyhow::Result<()> {
+        let mut seen = HashSet::new();
+        let keys: Vec<String> = keys
+            .into_iter()
+            .map(|key| key.trim().to_string())
+            .filter(|key| !key.is_empty() && seen.insert(key.clone()))
+            .collect();
+        if keys.is_empty() {
+            bail!("no OWM keys configured");
+        }
+        Redactor::global().add_secrets(keys.iter().cloned());
+        let mut slots = self.slots.lock().unwrap();
+        let new_slots = keys
+            .into_iter()
+            .map(|owm_key| {
+                let exhausted_until = slots
+                    .iter()
+                    .find(|slot| slot.key.owm_key == owm_key)
+                    .and_then(|slot| slot.exhausted_until);
+                KeySlot {
+                    key: APIKey { owm_key },
+                    exhausted_until,
+                }
+            })
+            .collect();
+        *slots = new_slots;
+        Ok(())
+    }
+
+    pub(crate) fn statuses(&self) -> Vec<KeyStatus> {
+        let now = Instant::now();
+        self.slots
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|slot| KeyStatus {
+                key: mask(&slot.key.owm_key),
+                exhausted_for_secs: slot
+                    .exhausted_until
+                    .filter(|until| *until > now)
+                    .map(|until| (until - now).as_secs()),
+            })
+            .collect()
+    }
+}
+
+fn mask(key: &str) -> String {
+    let visible = key.len().saturating_sub(4);
+    format!("...{}", key.get(visible..).unwrap_or_default())
+}
+
+/// Reads one key per line, skipping blank lines and `#` comments.
+pub(crate) fn read_keys_file(path: &Path) -> anyhow::Result<Vec<String>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("could not read keys file {}", path.display()))?;
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect())
+}
+
+/// Polls `path` and reloads the key ring whenever the file changes. The ring
+/// always keeps `static_keys`, the keys configured outside the file. A file
+/// that cannot be read leaves the current keys in place.
+pub(crate) async fn watch_keys_file(
+    ring: &KeyRing,
+    static_keys: Vec<String>,
+    path: PathBuf,
+    interval: Duration,
+) {
+    let modified = |path: &Path| -> Option<SystemTime> { path.metadata().ok()?.modified().ok() };
+    let mut last_modified = modified(&path);
+    loop {
+        tokio::time::sleep(interval).await;
+        let current = modified(&path);
+        if current == last_modified {
+            continue;
+        }
+        last_modified = current;
+        match reload_keys(ring, &static_keys, &path) {
+            Ok(()) => info!("Reloaded {} OWM keys from {}", ring.len(), path.display()),
+            Err(err) => warn!("Keeping current OWM keys: {:#}", err),
+        }
+    }
+}
+
+fn reload_keys(ring: &KeyRing, static_keys: &[String], path: &Path) -> anyhow::Result<()> {
+    let file_keys = read_keys_file(path)?;
+    // the same check the config gets at startup, short keys cannot be redacted.
+    if file_keys.iter().any(|key| key.len() < MIN_SECRET_LEN) {
+        bail!("OWM keys must be at least {MIN_SECRET_LEN} characters long");
+    }
+    let mut keys = static_keys.to_vec();
+    keys.extend(file_keys);
+    ring.replace(keys)
+}
+
+#[test]
+fn test_round_robin_skips_exhausted_keys() {
+    let ring = KeyRing::new(
+        vec![String::from("a"), String::from("b"), String::from("c")],
+        KeyStrategy::RoundRobin,
+        Duration::from_secs(60),
+    )
+    .unwrap();
+    let picks: Vec<String> = (0..3).map(|_| ring.pick().unwrap().owm_key).collect();
+    assert_eq!(picks, vec!["a", "b", "c"]);
+
+    ring.mark_exhausted(
+        &APIKey {
+            owm_key: String::from("b"),
+        },
+        None,
+    );
+    let picks: Vec<String> = (0..3).map(|_| ring.pick().unwrap().owm_key).collect();
+    assert!(!picks.contains(&String::from("b")));
+
+    ring.mark_exhausted(
+        &APIKey {
+            owm_key: String::from("a"),
+        },
+        None,
+    );
+    ring.mark_exhausted(
+        &APIKey {
+            owm_key: String::from("c"),
+        },
+        None,
+    );
+    assert!(ring.pick().is_none());
+}
+
+#[test]
+fn test_failover_and_reload() {
+    let ring = KeyRing::new(
+        vec![String::from("a"), String::from("b")],
+        KeyStrategy::Failover,
+        Duration::from_secs(60),
+    )
+    .unwrap();
+    assert_eq!(ring.pick().unwrap().owm_key, "a");
+    assert_eq!(ring.pick().unwrap().owm_key, "a");
+    ring.mark_exhausted(
+        &APIKey {
+            owm_key: String::from("a"),
+        },
+        None,
+    );
+    assert_eq!(ring.pick().unwrap().owm_key, "b");
+
+    // "a" stays exhausted across a reload, the new key is usable right away.
+    ring.replace(vec![String::from("a"), String::from("d")])
+        .unwrap();
+    assert_eq!(ring.pick().unwrap().owm_key, "d");
+    assert!(ring.replace(vec![String::from(" ")]).is_err());
+    assert_eq!(ring.len(), 2);
+}
+
+#[test]
+fn test_reload_keeps_static_keys() {
+    let path = std::env::temp_dir().join(format!("owm-keys-{}.txt", std::process::id()));
+    std::fs::write(&path, "file-key-a\nfile-key-b\n").unwrap();
+    let static_keys = vec![String::from("inline-key")];
+    let ring = KeyRing::new(
+        vec![
+            String::from("inline-key"),
+            String::from("file-key-a"),
+            String::from("file-key-b"),
+        ],
+        KeyStrategy::Failover,
+        Duration::from_secs(60),
+    )
+    .unwrap();
+    let keys = |ring: &KeyRing| -> Vec<String> {
+        ring.slots
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|slot| slot.key.owm_key.clone())
+            .collect()
+    };
+
+    std::fs::write(&path, "# rotated\nfile-key-c\n").unwrap();
+    reload_keys(&ring, &static_keys, &path).unwrap();
+    assert_eq!(keys(&ring), vec!["inline-key", "file-key-c"]);
+
+    // emptying the file leaves only the inline keys instead of failing.
+    std::fs::write(&path, "").unwrap();
+    reload_keys(&ring, &static_keys, &path).unwrap();
+    assert_eq!(keys(&ring), vec!["inline-key"]);
+
+    std::fs::write(&path, "short\n").unwrap();
+    assert!(reload_keys(&ring, &static_keys, &path).is_err());
+    std::fs::remove_file(&path).unwrap();
+    assert!(reload_keys(&ring, &static_keys, &path).is_err());
+    assert_eq!(keys(&ring), vec!["inline-key"]);
+}
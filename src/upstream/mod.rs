@@ -0,0 +1,123 @@
+use std::fmt::{Display, Formatter};
+
+use actix_web::http::StatusCode;
+use reqwest::Response;
+use serde::{Deserialize, Serialize};
+
+pub(crate) mod breaker;
+pub(crate) mod budget;
+pub(crate) mod client;
+pub(crate) mod keys;
+
+/// The OpenWeatherMap endpoints we call, each tracked separately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Endpoint {
+    #[serde(rename = "onecall")]
+    OneCall,
+    AirPollution,
+    Geo,
+}
+
+impl Endpoint {
+    pub(crate) const ALL: [Endpoint; 3] =
+        [Endpoint::OneCall, Endpoint::AirPollution, Endpoint::Geo];
+
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Endpoint::OneCall => "onecall",
+            Endpoint::AirPollution => "air_pollution",
+            Endpoint::Geo => "geo",
+        }
+    }
+}
+
+impl Display for Endpoint {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Errors raised when OpenWeatherMap is not called, or still fails after
+/// retries. Callers can fall back to stale cached data when they see one of
+/// these.
+#[derive(Debug)]
+pub(crate) enum UpstreamError {
+    CircuitOpen(Endpoint),
+    BudgetExhausted {
+        endpoint: Endpoint,
+        retry_after_secs: u64,
+    },
+    NoUsableKey,
+    // OWM answered, but not successfully.
+    Status {
+        endpoint: Endpoint,
+        status: u16,
+    },
+    // OWM answered successfully, but without the data asked for.
+    NoData(Endpoint),
+}
+
+impl UpstreamError {
+    pub(crate) fn status_code(&self) -> StatusCode {
+        match self {
+            UpstreamError::CircuitOpen(_) => StatusCode::SERVICE_UNAVAILABLE,
+            UpstreamError::BudgetExhausted { .. } => StatusCode::TOO_MANY_REQUESTS,
+            UpstreamError::NoUsableKey => StatusCode::SERVICE_UNAVAILABLE,
+            UpstreamError::Status { .. } | UpstreamError::NoData(_) => StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    /// When the request is worth repeating, for a `Retry-After` header.
+    pub(crate) fn retry_after_secs(&self) -> Option<u64> {
+        match self {
+            UpstreamError::BudgetExhausted {
+                retry_after_secs, ..
+            } => Some(*retry_after_secs),
+            _ => None,
+        }
+    }
+}
+
+impl Display for UpstreamError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UpstreamError::CircuitOpen(endpoint) => {
+                write!(f, "circuit breaker for {endpoint} is open")
+            }
+            UpstreamError::BudgetExhausted {
+                endpoint,
+                retry_after_secs,
+            } => write!(
+                f,
+                "call budget for {endpoint} is exhausted, resets in {retry_after_secs}s"
+            ),
+            UpstreamError::NoUsableKey => f.write_str("every OWM key is exhausted"),
+            UpstreamError::Status { endpoint, status } => {
+                write!(f, "{endpoint} answered with status {status}")
+            }
+            UpstreamError::NoData(endpoint) => write!(f, "{endpoint} answered without data"),
+        }
+    }
+}
+
+impl std::error::Error for UpstreamError {}
+
+/// Returns true if the error means the upstream was not called at all, or
+/// did not answer successfully.
+pub(crate) fn is_unavailable(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<UpstreamError>().is_some()
+}
+
+/// Passes `response` on if OWM answered successfully, and fails with
+/// `UpstreamError::Status` otherwise.
+pub(crate) fn success(endpoint: Endpoint, response: Response) -> Result<Response, UpstreamError> {
+    if response.status().is_success() {
+        Ok(response)
+    } else {
+        Err(UpstreamError::Status {
+            endpoint,
+            status: response.status().as_u16(),
+        })
+    }
+}
@@ -0,0 +1,108 @@
+use actix_web::http::StatusCode;
+
+use crate::{
+    entities::Location,
+    errors::rejected,
+    weather::{entities::Units, sections::Sections},
+};
+
+// decimal places kept of client coordinates, about 1m. Finer positions only
+// fragment logs and metrics, the cache serves kilometers anyway.
+const COORDINATE_DECIMALS: i32 = 5;
+
+/// Checks coordinates given by a client and normalizes them: latitude must be
+/// within ±90 and longitude within ±360, which is wrapped into [-180, 180).
+/// Both are rounded to `COORDINATE_DECIMALS` places.
+pub(crate) fn normalize_location(latitude: f64, longitude: f64) -> Result<Location, &'static str> {
+    if !latitude.is_finite() || !longitude.is_finite() {
+        return Err("coordinates must be finite numbers");
+    }
+    if !(-90.0..=90.0).contains(&latitude) {
+        return Err("latitude must be between -90 and 90");
+    }
+    if !(-360.0..=360.0).contains(&longitude) {
+        return Err("longitude must be between -360 and 360");
+    }
+    let scale = 10f64.powi(COORDINATE_DECIMALS);
+    let round = |value: f64| (value * scale).round() / scale;
+    // rounded first, so 179.999999 wraps to -180 too.
+    let mut longitude = round(longitude);
+    if !(-180.0..180.0).contains(&longitude) {
+        longitude = round((longitude + 180.0).rem_euclid(360.0) - 180.0);
+    }
+    Ok(Location {
+        latitude: round(latitude),
+        longitude,
+    })
+}
+
+/// The location of a request, or a 400 response.
+pub(crate) fn location(latitude: f64, longitude: f64) -> actix_web::Result<Location> {
+    normalize_location(latitude, longitude)
+        .map_err(|message| rejected(message, StatusCode::BAD_REQUEST))
+}
+
+/// The units of a request, or a 400 response.
+pub(crate) fn units(units: &str) -> actix_web::Result<Units> {
+    units.parse().map_err(|_| {
+        rejected(
+            "units must be metric, imperial or standard",
+            StatusCode::BAD_REQUEST,
+        )
+    })
+}
+
+/// The sections of a weather response a request includes, or a 400 response.
+pub(crate) fn sections(include: &str) -> actix_web::Result<Sections> {
+    include.parse().map_err(|_| {
+        rejected(
+            "include must list some of current, hourly, daily, minutely, alerts, aqi and geocode",
+            StatusCode::BAD_REQUEST,
+        )
+    })
+}
+
+/// An OWM language code such as `en` or `zh_cn`, lowercased, or a 400 response.
+pub(crate) fn lang(lang: &str) -> actix_web::Result<String> {
+    let lang = lang.to_ascii_lowercase();
+    let valid = match lang.split_once('_') {
+        Some((language, region)) => is_code(language) && is_code(region),
+        None => is_code(&lang),
+    };
+    if !valid {
+        return Err(rejected(
+            "lang must be a language code such as en or pt_br",
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+    Ok(lang)
+}
+
+fn is_code(code: &str) -> bool {
+    code.len() == 2 && code.bytes().all(|byte| byte.is_ascii_lowercase())
+}
+
+#[test]
+fn test_normalize_location() {
+    let normalized = |latitude, longitude| {
+        normalize_location(latitude, longitude)
+            .map(|location| (location.latitude, location.longitude))
+    };
+    assert_eq!(normalized(37.1234567, -122.0), Ok((37.12346, -122.0)));
+    assert_eq!(normalized(-90.0, 190.0), Ok((-90.0, -170.0)));
+    assert_eq!(normalized(0.0, 180.0), Ok((0.0, -180.0)));
+    assert_eq!(normalized(0.0, 179.999999), Ok((0.0, -180.0)));
+    assert_eq!(normalized(0.0, -360.0), Ok((0.0, 0.0)));
+    assert_eq!(normalized(51.5074, -0.1278), Ok((51.5074, -0.1278)));
+    assert!(normalized(f64::NAN, 0.0).is_err());
+    assert!(normalized(0.0, f64::INFINITY).is_err());
+    assert!(normalized(90.5, 0.0).is_err());
+    assert!(normalized(0.0, 1000.0).is_err());
+    assert!(normalized(-500.0, 0.0).is_err());
+    assert!(units("metric").is_ok());
+    assert!(units("Metric").is_err());
+    assert!(units("kelvin").is_err());
+    assert_eq!(lang("pt_BR").unwrap(), "pt_br");
+    assert!(lang("english").is_err());
+    assert!(lang("e1").is_err());
+}
@@ -1,10 +1,12 @@
 use std::{
     collections::HashMap,
     fmt::{Display, Formatter},
+    str::FromStr,
 };
 
-use crate::{geocoding::entities::ReverseGeocode, weather_proto::weather_message};
-use chrono::{DateTime, Utc};
+use anyhow::bail;
+
+use crate::{cache::CacheValue, weather_proto::weather_message};
 use protobuf::EnumOrUnknown;
 
 pub(crate) trait ProtoAdapter {
@@ -41,6 +43,7 @@ impl ProtoAdapter for Conditions {
     }
 }
 
+#[allow(dead_code)]
 pub(crate) struct OneDayForecast {
     pub(crate) high_temp: f64,
     pub(crate) low_temp: f64,
@@ -68,6 +71,7 @@ impl ProtoAdapter for OneDayForecast {
     }
 }
 
+#[allow(dead_code)]
 pub(crate) struct HourlyWeather {
     pub(crate) temp: f64,
     pub(crate) feels_like: f64,
@@ -89,6 +93,7 @@ impl ProtoAdapter for HourlyWeather {
     }
 }
 
+#[allow(dead_code)]
 struct WeatherInfo {
     forecasts: Vec<OneDayForecast>,
     hour_forecasts: Vec<HourlyWeather>,
@@ -112,7 +117,7 @@ impl ProtoAdapter for WeatherInfo {
     }
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum Units {
     Metric,
     Imperial,
@@ -129,12 +134,18 @@ impl Display for Units {
     }
 }
 
-impl From<String> for Units {
-    fn from(unit: String) -> Self {
-        match unit.as_str() {
-            "metric" => Units::Metric,
-            "imperial" => Units::Imperial,
-            _ => Units::Standard,
+impl FromStr for Units {
+    type Err = anyhow::Error;
+
+    fn from_str(unit: &str) -> anyhow::Result<Self> {
+        match unit {
+            "metric" => Ok(Units::Metric),
+            "imperial" => Ok(Units::Imperial),
+            "standard" => Ok(Units::Standard),
+            _ => bail!(
+                "unknown units {:?}, expected metric, imperial or standard",
+                unit
+            ),
         }
     }
 }
@@ -154,7 +165,7 @@ impl From<String> for Units {
 
 use serde::{Deserialize, Serialize};
 
-use super::utils::convert_id_to_condition;
+use super::utils::{convert_aqi_to_string, convert_id_to_condition};
 
 // Keep up to date with https://openweathermap.org/api/one-call-3#parameter.
 
@@ -187,9 +198,13 @@ pub struct WeatherResponse {
     pub(crate) lon: f64,
     pub(crate) timezone: String,
     pub(crate) timezone_offset: i64,
-    pub(crate) current: Current,
+    // OWM leaves out the parts named in `exclude`.
+    pub(crate) current: Option<Current>,
+    #[serde(default)]
     pub(crate) minutely: Vec<Minutely>,
+    #[serde(default)]
     pub(crate) hourly: Vec<Current>,
+    #[serde(default)]
     pub(crate) daily: Vec<Daily>,
     pub(crate) alerts: Option<Vec<WeatherAlert>>,
 }
@@ -381,10 +396,45 @@ pub struct AqiMain {
     pub(crate) aqi: i64,
 }
 
-#[derive(Debug)]
-pub struct CachedData {
+/// A onecall response as cached in the weather tier, without the AQI and
+/// place name which are cached separately.
+#[derive(Debug, Clone)]
+pub struct CachedForecast {
     pub weather: weather_message::WeatherInfo,
-    pub reverse_geocode: ReverseGeocode,
-    pub expiry: DateTime<Utc>,
-    pub temp_unit: Units,
+    pub units: Units,
+    // fetched with only the daily forecast.
+    pub daily_only: bool,
+}
+
+impl CachedForecast {
+    /// Whether this entry can answer a request in `units`. Daily-only entries
+    /// only answer requests that are daily-only themselves.
+    pub(crate) fn serves(&self, units: Units, daily_only: bool) -> bool {
+        self.units == units && (daily_only || !self.daily_only)
+    }
+}
+
+impl CacheValue for CachedForecast {
+    fn describe(&self) -> String {
+        if self.daily_only {
+            format!("{}, daily only", self.units)
+        } else {
+            self.units.to_string()
+        }
+    }
+
+    fn key_part(&self) -> Option<String> {
+        if self.daily_only {
+            Some(format!("{}:daily", self.units))
+        } else {
+            Some(self.units.to_string())
+        }
+    }
+}
+
+// the AQI tier caches the raw OWM index.
+impl CacheValue for i64 {
+    fn describe(&self) -> String {
+        convert_aqi_to_string(*self)
+    }
 }
@@ -0,0 +1,202 @@
+use serde::Deserialize;
+
+use crate::weather_proto::weather_message::{self, Bounds, Interpolation, WeatherInfo};
+
+/// Serves requests that fall between cached locations by inverse distance
+/// weighting of the nearest fresh weather entries instead of calling OWM.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct InterpolationConfig {
+    pub(crate) enabled: bool,
+    // fresh entries needed, all within radius_km of the query.
+    pub(crate) neighbors: usize,
+    pub(crate) radius_km: f64,
+    // higher powers favour the closest entries.
+    pub(crate) power: f64,
+}
+
+impl Default for InterpolationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            neighbors: 3,
+            radius_km: 25.0,
+            power: 2.0,
+        }
+    }
+}
+
+// entries closer than this are treated as being at the query point.
+const MIN_DISTANCE_KM: f64 = 0.001;
+
+/// Interpolates temperature, wind and precipitation at a point from
+/// `neighbors`, given as (distance in km, weather) pairs. Conditions, alerts
+/// and timestamps are taken from the nearest neighbor. Hours, days and
+/// minutes are matched by time; a value missing from some neighbors is
+/// interpolated from the others.
+pub(crate) fn interpolate(neighbors: &[(f64, WeatherInfo)], power: f64) -> Option<WeatherInfo> {
+    let (_, nearest) = neighbors.iter().min_by(|(a, _), (b, _)| a.total_cmp(b))?;
+    let weights: Vec<f64> = neighbors
+        .iter()
+        .map(|(distance, _)| 1.0 / distance.max(MIN_DISTANCE_KM).powf(power))
+        .collect();
+    let estimate = |value: &dyn Fn(&WeatherInfo) -> Option<f64>| {
+        idw(weights
+            .iter()
+            .zip(neighbors)
+            .filter_map(|(weight, (_, weather))| Some((*weight, value(weather)?))))
+    };
+
+    let mut weather = nearest.clone();
+    if let Some(current) = weather.current_weather.as_mut() {
+        let current_value = |value: fn(&weather_message::HourlyWeather) -> f64| {
+            move |weather: &WeatherInfo| weather.current_weather.as_ref().map(value)
+        };
+        current.temp = estimate(&current_value(|hour| hour.temp)).unwrap_or(current.temp);
+        current.feels_like =
+            estimate(&current_value(|hour| hour.feels_like)).unwrap_or(current.feels_like);
+    }
+    weather.wind_speed =
+        estimate(&|weather| Some(f64::from(weather.wind_speed))).unwrap_or_default() as f32;
+    for hour in weather.hour_forecasts.iter_mut() {
+        let time = hour.time;
+        let at = |weather: &WeatherInfo| {
+            weather
+                .hour_forecasts
+                .iter()
+                .find(|other| other.time == time)
+                .cloned()
+        };
+        hour.temp = estimate(&|weather| at(weather).map(|hour| hour.temp)).unwrap_or(hour.temp);
+        hour.feels_like =
+            estimate(&|weather| at(weather).map(|hour| hour.feels_like)).unwrap_or(hour.feels_like);
+    }
+    for day in weather.forecasts.iter_mut() {
+        let time = day.time;
+        let at = |weather: &WeatherInfo| {
+            weather
+                .forecasts
+                .iter()
+                .find(|other| other.time == time)
+                .cloned()
+        };
+        day.low_temp =
+            estimate(&|weather| at(weather).map(|day| day.low_temp)).unwrap_or(day.low_temp);
+        day.high_temp =
+            estimate(&|weather| at(weather).map(|day| day.high_temp)).unwrap_or(day.high_temp);
+        day.rain = estimate(&|weather| at(weather).map(|day| day.rain)).unwrap_or(day.rain);
+    }
+    for minute in weather.minutely_rain.iter_mut() {
+        let time = minute.time;
+        minute.rain = estimate(&|weather| {
+            weather
+                .minutely_rain
+                .iter()
+                .find(|other| other.time == time)
+                .map(|other| other.rain)
+        })
+        .unwrap_or(minute.rain);
+    }
+
+    let today = weather.forecasts.first().map(|day| day.time);
+    weather.interpolation = Some(Interpolation {
+        neighbors: neighbors.len() as u32,
+        max_distance_km: neighbors
+            .iter()
+            .map(|(distance, _)| *distance)
+            .fold(0.0, f64::max),
+        temp: bounds(neighbors, |weather| {
+            weather.current_weather.as_ref().map(|current| current.temp)
+        }),
+        wind_speed: bounds(neighbors, |weather| Some(f64::from(weather.wind_speed))),
+        precipitation: bounds(neighbors, |weather| {
+            weather
+                .forecasts
+                .iter()
+                .find(|day| Some(day.time) == today)
+                .map(|day| day.rain)
+        }),
+        ..Default::default()
+    })
+    .into();
+    Some(weather)
+}
+
+// the weighted mean of (weight, value) pairs.
+fn idw(values: impl Iterator<Item = (f64, f64)>) -> Option<f64> {
+    let (weighted, total) = values.fold((0.0, 0.0), |(weighted, total), (weight, value)| {
+        (weighted + weight * value, total + weight)
+    });
+    (total > 0.0).then(|| weighted / total)
+}
+
+fn bounds(
+    neighbors: &[(f64, WeatherInfo)],
+    value: impl Fn(&WeatherInfo) -> Option<f64>,
+) -> protobuf::MessageField<Bounds> {
+    let values: Vec<f64> = neighbors
+        .iter()
+        .filter_map(|(_, weather)| value(weather))
+        .collect();
+    if values.is_empty() {
+        return None.into();
+    }
+    Some(Bounds {
+        low: values.iter().copied().fold(f64::INFINITY, f64::min),
+        high: values.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+        ..Default::default()
+    })
+    .into()
+}
+
+#[test]
+fn test_interpolation_weights_by_distance() {
+    use weather_message::{HourlyWeather, OneDayForecast};
+
+    let weather = |temp: f64, wind_speed: f32, rain: f64| WeatherInfo {
+        current_weather: Some(HourlyWeather {
+            temp,
+            time: 100,
+            ..Default::default()
+        })
+        .into(),
+        wind_speed,
+        forecasts: vec![OneDayForecast {
+            time: 86_400,
+            rain,
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    let neighbors = vec![
+        (10.0, weather(10.0, 2.0, 0.0)),
+        (10.0, weather(20.0, 6.0, 4.0)),
+        (20.0, weather(30.0, 4.0, 8.0)),
+    ];
+    let interpolated = interpolate(&neighbors, 2.0).unwrap();
+
+    // weights 1/100, 1/100 and 1/400.
+    let temp = interpolated.current_weather.temp;
+    assert!((temp - (10.0 * 4.0 + 20.0 * 4.0 + 30.0) / 9.0).abs() < 1e-9);
+    assert!(
+        (f64::from(interpolated.wind_speed) - (2.0 * 4.0 + 6.0 * 4.0 + 4.0) / 9.0).abs() < 1e-5
+    );
+    assert!((interpolated.forecasts[0].rain - (4.0 * 4.0 + 8.0) / 9.0).abs() < 1e-9);
+
+    let interpolation = interpolated.interpolation.unwrap();
+    assert_eq!(interpolation.neighbors, 3);
+    assert_eq!(interpolation.max_distance_km, 20.0);
+    assert_eq!(
+        (interpolation.temp.low, interpolation.temp.high),
+        (10.0, 30.0)
+    );
+    assert_eq!(
+        (
+            interpolation.precipitation.low,
+            interpolation.precipitation.high
+        ),
+        (0.0, 8.0)
+    );
+
+    assert!(interpolate(&[], 2.0).is_none());
+}
@@ -1,122 +1,276 @@
 use actix_web::web;
 use chrono::{Duration, Utc};
 use protobuf::Message;
-use reqwest::StatusCode;
-use tracing::debug;
+use tracing::{debug, Span};
 
 use crate::{
-    entities::Location,
+    cache::{Fetched, Tier},
+    entities::{CacheStatus, Location},
     geocoding,
+    upstream::{self, keys::APIKey, Endpoint, UpstreamError},
     weather::{
         entities::{ProtoAdapter, WeatherResponse},
+        interpolation,
         utils::convert_aqi_to_string,
     },
     weather_proto::weather_message,
-    APIKey, AppState,
+    AppState,
 };
 
-use super::entities::{AqiResponse, CachedData, Units};
+use super::{
+    entities::{AqiResponse, CachedForecast, Units},
+    sections::Sections,
+};
 
-pub(crate) async fn do_aqi_query(keys: &APIKey, location: &Location) -> anyhow::Result<i64> {
-    let owm_query = format!(
-        "http://api.openweathermap.org/data/2.5/air_pollution?lat={}&lon={}&appid={}",
-        location.latitude, location.longitude, keys.owm_key
-    );
-    let result = reqwest::get(owm_query).await?;
-    let response_mapping = result.json::<AqiResponse>().await.unwrap();
-    let aqi = response_mapping.list[0].main.aqi;
+/// The AQI of `location`, from the AQI tier of the cache when possible.
+#[tracing::instrument(
+    skip(data),
+    fields(cache.status = tracing::field::Empty, cache.distance_km = tracing::field::Empty)
+)]
+pub(crate) async fn do_aqi_query(
+    location: &Location,
+    data: &web::Data<AppState>,
+) -> anyhow::Result<(i64, CacheStatus)> {
+    let cache = &data.caches.aqi;
+    cache
+        .get_or_fetch(
+            location,
+            |_| true,
+            &data.metrics,
+            || async {
+                Ok(Fetched::Cache(
+                    fetch_aqi(location, data).await?,
+                    cache.config().ttl,
+                ))
+            },
+        )
+        .await
+}
+
+async fn fetch_aqi(location: &Location, data: &web::Data<AppState>) -> anyhow::Result<i64> {
+    let owm_query = |keys: &APIKey| {
+        format!(
+            "{}/data/2.5/air_pollution?lat={}&lon={}&appid={}",
+            data.config.owm.base_url, location.latitude, location.longitude, keys.owm_key
+        )
+    };
+    let response = data
+        .http_client
+        .get(Endpoint::AirPollution, owm_query)
+        .await?;
+    let response = upstream::success(Endpoint::AirPollution, response)?;
+    let response_mapping = response
+        .json::<AqiResponse>()
+        .await
+        .map_err(reqwest::Error::without_url)?;
+    let aqi = response_mapping
+        .list
+        .first()
+        .ok_or(UpstreamError::NoData(Endpoint::AirPollution))?
+        .main
+        .aqi;
     Ok(aqi)
 }
 
+/// Assembles the `sections` of the weather response for `location` from the
+/// weather, AQI and reverse geocode tiers of the cache, fetching whatever is
+/// not fresh. Tiers no included section needs are not consulted. The place
+/// name is given in `lang` when OWM knows it.
+#[tracing::instrument(skip(data), fields(cache.status = tracing::field::Empty))]
 pub(crate) async fn do_weather_query(
-    keys: APIKey,
     location: Location,
     units: Units,
+    sections: Sections,
+    lang: Option<&str>,
     data: web::Data<AppState>,
-) -> anyhow::Result<Vec<u8>> {
-    {
-        // lock kdtree and cached data hashmap
-        let mut kdtree = data.kdtree.lock().unwrap();
-        let mut cached_data = data.cached_data.lock().unwrap();
-        debug!("Locked kdtree and cache hashmap");
-
-        // convert point to 3d coordinates
-        let query = [location.latitude, location.longitude];
-
-        // query nearest single point
-        let (dist, nearest_idx) = kdtree.nearest_one(&query, &crate::weather::utils::haversine);
-
-        debug!("Distance from given point {}km", dist);
-        debug!("Points in kdtree {}", kdtree.size());
-
-        // if nearest point is less than 10km away, we can use it
-        if dist < 10.0 {
-            debug!("Nearest point is {}km", dist);
-            // return result from hashmap
-            if let Some(cached_res) = cached_data.get(&nearest_idx) {
-                debug!("Hashmap contains data for this index");
-                // if unit is the same, we can pull from cache.
-                if cached_res.temp_unit == units {
-                    // if data is not yet stale, return it
-                    if cached_res.expiry > Utc::now() {
-                        debug!("Returned data is not yet stale");
-                        return Ok(cached_res.weather.write_to_bytes().unwrap());
-                    } else {
-                        debug!("Returned data is stale. Clearing cache.");
-                        kdtree.remove(&query, nearest_idx);
-                        cached_data.remove(&nearest_idx);
-                    }
-                }
-            }
+) -> anyhow::Result<(Vec<u8>, CacheStatus)> {
+    let mut weather = weather_message::WeatherInfo::default();
+    let mut statuses = vec![];
+    if sections.forecast() {
+        let (forecast, status) =
+            get_forecast(&location, units, sections.daily_only(), &data).await?;
+        weather = forecast.weather;
+        sections.retain(&mut weather);
+        statuses.push(status);
+    }
+    if sections.aqi {
+        let (aqi, status) = do_aqi_query(&location, &data).await?;
+        weather.aqi = convert_aqi_to_string(aqi);
+        statuses.push(status);
+    }
+    if sections.geocode {
+        let (reverse_geocode, status) =
+            geocoding::methods::do_reverse_geocode(&location, &data).await?;
+        weather.geocode = Some(reverse_geocode.to_proto_in(lang)).into();
+        statuses.push(status);
+    }
+
+    let status = CacheStatus::combine(statuses);
+    Span::current().record("cache.status", status.as_str());
+    Ok((weather.write_to_bytes().unwrap(), status))
+}
+
+#[tracing::instrument(
+    skip(data),
+    fields(cache.status = tracing::field::Empty, cache.distance_km = tracing::field::Empty)
+)]
+async fn get_forecast(
+    location: &Location,
+    units: Units,
+    daily_only: bool,
+    data: &web::Data<AppState>,
+) -> anyhow::Result<(CachedForecast, CacheStatus)> {
+    let cache = &data.caches.weather;
+    if data.config.cache.interpolation.enabled {
+        if let Some(forecast) = interpolate_forecast(location, units, daily_only, data) {
+            data.metrics
+                .record_cache(cache.name(), CacheStatus::Interpolated);
+            Span::current().record("cache.status", CacheStatus::Interpolated.as_str());
+            return Ok((forecast, CacheStatus::Interpolated));
         }
-        debug!("Nearest point is more than 10kmm querying OWM");
     }
+    cache
+        .get_or_fetch(
+            location,
+            |forecast| forecast.serves(units, daily_only),
+            &data.metrics,
+            || async {
+                let forecast = fetch_forecast(location, units, daily_only, data).await?;
+                let ttl = forecast_ttl(data, &forecast.weather);
+                Ok(Fetched::Cache(forecast, ttl))
+            },
+        )
+        .await
+}
 
-    // otherwise, query OWM for weather data
-    let owm_query = format!(
-        "http://api.openweathermap.org/data/3.0/onecall?lat={}&lon={}&appid={}&units={}",
-        location.latitude, location.longitude, keys.owm_key, units
-    );
+// estimates the forecast from the nearest fresh entries when no fresh entry is
+// close enough to serve it and enough of them are within the interpolation
+// radius.
+fn interpolate_forecast(
+    location: &Location,
+    units: Units,
+    daily_only: bool,
+    data: &AppState,
+) -> Option<CachedForecast> {
+    let cache = &data.caches.weather;
+    let config = &data.config.cache.interpolation;
+    let matches = |forecast: &CachedForecast| forecast.serves(units, daily_only);
+    let served_fresh = cache
+        .find(location, matches)
+        .and_then(|(id, _)| cache.entry(id))
+        .is_some_and(|entry| entry.expiry > Utc::now());
+    if served_fresh {
+        return None;
+    }
+    let neighbors: Vec<(f64, weather_message::WeatherInfo)> = cache
+        .fresh_neighbors(location, config.radius_km, config.neighbors, matches)
+        .into_iter()
+        .map(|(distance, forecast)| (distance, forecast.weather))
+        .collect();
+    if neighbors.len() < config.neighbors {
+        return None;
+    }
+    debug!("Interpolating weather from {} entries", neighbors.len());
+    let weather = interpolation::interpolate(&neighbors, config.power)?;
+    Some(CachedForecast {
+        weather,
+        units,
+        daily_only,
+    })
+}
+
+/// Fetches the forecast for `location` from OWM regardless of what is cached
+/// and replaces the weather entry that served it, if any. Returns the id of
+/// the new entry.
+pub(crate) async fn refresh_weather(
+    location: Location,
+    units: Units,
+    data: &web::Data<AppState>,
+) -> anyhow::Result<usize> {
+    let cache = &data.caches.weather;
+    let replaces = cache
+        .find(&location, |forecast| forecast.serves(units, false))
+        .map(|(id, _)| id);
+    let forecast = fetch_forecast(&location, units, false, data).await?;
+    let ttl = forecast_ttl(data, &forecast.weather);
+    Ok(cache.insert(location, forecast, ttl, replaces))
+}
+
+// volatile weather expires sooner than stable weather.
+fn forecast_ttl(data: &AppState, weather: &weather_message::WeatherInfo) -> Duration {
+    let cache = &data.config.cache;
+    let ttl_minutes = cache
+        .adaptive_ttl
+        .ttl_minutes(weather, cache.ttl_minutes, Utc::now());
+    debug!("Caching weather for {} minutes", ttl_minutes);
+    Duration::minutes(ttl_minutes)
+}
 
-    debug!("OWM query: {}", owm_query);
-    let response = reqwest::get(owm_query).await?;
+// Queries OWM onecall for the forecast of `location`, only the daily part of
+// it if `daily_only`.
+async fn fetch_forecast(
+    location: &Location,
+    units: Units,
+    daily_only: bool,
+    data: &web::Data<AppState>,
+) -> anyhow::Result<CachedForecast> {
+    let exclude = if daily_only {
+        "&exclude=current,minutely,hourly,alerts"
+    } else {
+        ""
+    };
+    let owm_query = |keys: &APIKey| {
+        format!(
+            "{}/data/3.0/onecall?lat={}&lon={}&appid={}&units={}{}",
+            data.config.owm.base_url,
+            location.latitude,
+            location.longitude,
+            keys.owm_key,
+            units,
+            exclude
+        )
+    };
+
+    debug!(
+        "Querying OWM onecall for {}, {}",
+        location.latitude, location.longitude
+    );
+    let response = data.http_client.get(Endpoint::OneCall, owm_query).await?;
 
     debug!("Got response from OWM");
 
-    if !StatusCode::is_success(&response.status()) {
-        // Our request failed for some reason, we will try again later.
-        return Ok(
-            format!("request failed with statuscode: {}", &response.status())
-                .as_bytes()
-                .to_vec(),
-        );
-    }
+    // Our request failed for some reason, a stale entry can stand in for now.
+    let response = upstream::success(Endpoint::OneCall, response)?;
 
     // deserialize OWM response
-    let response_mapping = response.json::<WeatherResponse>().await.unwrap();
+    let response_mapping = response
+        .json::<WeatherResponse>()
+        .await
+        .map_err(reqwest::Error::without_url)?;
 
     debug!("Deserialized response");
 
-    // fetch reverse geocode for location
-    let reverse_geocode = geocoding::methods::do_reverse_geocode(&keys, &location, &data).await?;
-
-    debug!("Fetched reverse geocode");
-
     // construct response
-    let final_weather = weather_message::WeatherInfo {
+    let weather = weather_message::WeatherInfo {
         hour_forecasts: response_mapping
             .hourly
             .iter()
             .map(|w| w.to_proto())
             .collect(),
-        current_weather: Some(response_mapping.current.to_proto()).into(),
+        current_weather: response_mapping
+            .current
+            .as_ref()
+            .map(|current| current.to_proto())
+            .into(),
+        wind_speed: response_mapping
+            .current
+            .as_ref()
+            .map_or(0.0, |current| current.wind_speed as f32),
         forecasts: response_mapping
             .daily
             .iter()
             .map(|w| w.to_proto())
             .collect(),
-        aqi: convert_aqi_to_string(do_aqi_query(&keys, &location).await?),
-        geocode: Some(reverse_geocode.to_proto()).into(),
         alerts: response_mapping
             .alerts
             .unwrap_or(vec![])
@@ -131,26 +285,77 @@ pub(crate) async fn do_weather_query(
         ..Default::default()
     };
 
-    // re-acquire locks, we need to write some data to cache
-    let mut kdtree = data.kdtree.lock().unwrap();
-    let mut cached_data = data.cached_data.lock().unwrap();
-
-    // generate incremental index
-    let index = cached_data.len() + 1;
-
-    // insert index and cached data into hashmap
-    cached_data.insert(
-        index,
-        CachedData {
-            weather: final_weather.clone(),
-            reverse_geocode,
-            expiry: Utc::now() + Duration::minutes(15),
-            temp_unit: units,
+    Ok(CachedForecast {
+        weather,
+        units,
+        daily_only,
+    })
+}
+
+#[actix_web::test]
+async fn test_serves_stale_forecast_when_owm_fails() {
+    let mut config = crate::config::Config::default();
+    config.owm.api_key = String::from("owm-key");
+    config.owm.base_url = crate::fake_owm(vec!["503 Service Unavailable"]).await;
+    config.http.max_retries = 0;
+    let data = crate::test_state(config);
+    let location = Location::at(37.5, -122.0);
+
+    let err = get_forecast(&location, Units::Metric, false, &data)
+        .await
+        .unwrap_err();
+    assert!(upstream::is_unavailable(&err));
+
+    data.caches.weather.insert(
+        location,
+        CachedForecast {
+            weather: Default::default(),
+            units: Units::Metric,
+            daily_only: false,
         },
+        Duration::minutes(-1),
+        None,
     );
+    let (forecast, status) = get_forecast(&location, Units::Metric, false, &data)
+        .await
+        .unwrap();
+    assert_eq!(status, CacheStatus::Stale);
+    assert_eq!(forecast.units, Units::Metric);
+}
+
+#[actix_web::test]
+async fn test_daily_only_entries_get_the_daily_only_ttl() {
+    let mut config = crate::config::Config::default();
+    config.owm.api_key = String::from("owm-key");
+    // what onecall answers with exclude=current,minutely,hourly,alerts.
+    config.owm.base_url = crate::fake_owm(vec![
+        "200 OK\r\ncontent-type: application/json\r\n\r\n\
+         {\"lat\":37.5,\"lon\":-122.0,\"timezone\":\"UTC\",\"timezone_offset\":0,\"daily\":[]}",
+    ])
+    .await;
+    let data = crate::test_state(config);
+    let location = Location::at(37.5, -122.0);
 
-    // add index to kdtree
-    kdtree.add(&[location.latitude, location.longitude], index);
+    let (forecast, status) = get_forecast(&location, Units::Metric, true, &data)
+        .await
+        .unwrap();
+    assert_eq!(status, CacheStatus::Miss);
+    assert!(forecast.daily_only);
+    let cache = &data.caches.weather;
+    let (id, _) = cache
+        .find(&location, |forecast| forecast.serves(Units::Metric, true))
+        .unwrap();
+    let ttl = cache.entry(id).unwrap().expiry - Utc::now();
+    let daily_only_minutes = data.config.cache.adaptive_ttl.daily_only_minutes;
+    assert!(ttl > Duration::minutes(daily_only_minutes - 1));
+    assert!(ttl <= Duration::minutes(daily_only_minutes));
 
-    Ok(final_weather.write_to_bytes().unwrap())
+    // a request for more than the daily forecast is not served the entry.
+    assert!(cache
+        .find(&location, |forecast| forecast.serves(Units::Metric, false))
+        .is_none());
+    let (_, status) = get_forecast(&location, Units::Metric, true, &data)
+        .await
+        .unwrap();
+    assert_eq!(status, CacheStatus::Hit);
 }
@@ -1,3 +1,7 @@
 pub(crate) mod entities;
+pub(crate) mod interpolation;
 pub(crate) mod methods;
-pub(crate) mod utils;
\ No newline at end of file
+pub(crate) mod sections;
+pub(crate) mod ttl;
+pub(crate) mod utils;
+pub(crate) mod warming;
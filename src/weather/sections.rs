@@ -0,0 +1,121 @@
+use std::str::FromStr;
+
+use anyhow::bail;
+
+use crate::weather_proto::weather_message::WeatherInfo;
+
+/// The parts of a weather response a client asked for. The forecast, AQI and
+/// place name are only looked up when a part needing them is included.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Sections {
+    // current conditions and wind speed.
+    pub(crate) current: bool,
+    pub(crate) hourly: bool,
+    pub(crate) daily: bool,
+    pub(crate) minutely: bool,
+    pub(crate) alerts: bool,
+    pub(crate) aqi: bool,
+    pub(crate) geocode: bool,
+}
+
+impl Sections {
+    pub(crate) const ALL: Sections = Sections {
+        current: true,
+        hourly: true,
+        daily: true,
+        minutely: true,
+        alerts: true,
+        aqi: true,
+        geocode: true,
+    };
+
+    /// Whether any included part comes from the OWM forecast.
+    pub(crate) fn forecast(&self) -> bool {
+        self.current || self.hourly || self.daily || self.minutely || self.alerts
+    }
+
+    /// Whether the daily forecast is the only part of the OWM forecast
+    /// included, so the rest need not be fetched.
+    pub(crate) fn daily_only(&self) -> bool {
+        self.daily && !(self.current || self.hourly || self.minutely || self.alerts)
+    }
+
+    /// Clears the forecast parts of `weather` that were not asked for.
+    pub(crate) fn retain(&self, weather: &mut WeatherInfo) {
+        if !self.current {
+            weather.current_weather.clear();
+            weather.wind_speed = 0.0;
+        }
+        if !self.hourly {
+            weather.hour_forecasts.clear();
+        }
+        if !self.daily {
+            weather.forecasts.clear();
+        }
+        if !self.minutely {
+            weather.minutely_rain.clear();
+        }
+        if !self.alerts {
+            weather.alerts.clear();
+        }
+    }
+}
+
+/// Parses a comma separated list such as `current,daily,aqi`.
+impl FromStr for Sections {
+    type Err = anyhow::Error;
+
+    fn from_str(include: &str) -> anyhow::Result<Self> {
+        let mut sections = Sections::default();
+        for name in include
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+        {
+            let section = match name {
+                "current" => &mut sections.current,
+                "hourly" => &mut sections.hourly,
+                "daily" => &mut sections.daily,
+                "minutely" => &mut sections.minutely,
+                "alerts" => &mut sections.alerts,
+                "aqi" => &mut sections.aqi,
+                "geocode" => &mut sections.geocode,
+                _ => bail!("unknown section {:?}", name),
+            };
+            *section = true;
+        }
+        if sections == Sections::default() {
+            bail!("no section included");
+        }
+        Ok(sections)
+    }
+}
+
+#[test]
+fn test_parse_and_retain_sections() {
+    use crate::weather_proto::weather_message::{HourlyWeather, OneDayForecast};
+
+    let sections: Sections = "current, daily,aqi".parse().unwrap();
+    assert!(sections.current && sections.daily && sections.aqi);
+    assert!(!sections.hourly && !sections.geocode);
+    assert!(sections.forecast());
+    assert!(!"aqi,geocode".parse::<Sections>().unwrap().forecast());
+    assert!("daily,aqi".parse::<Sections>().unwrap().daily_only());
+    assert!(!sections.daily_only());
+    assert!(!Sections::ALL.daily_only());
+    assert!("current,weekly".parse::<Sections>().is_err());
+    assert!("".parse::<Sections>().is_err());
+
+    let mut weather = WeatherInfo {
+        current_weather: Some(HourlyWeather::default()).into(),
+        wind_speed: 3.0,
+        hour_forecasts: vec![HourlyWeather::default()],
+        forecasts: vec![OneDayForecast::default()],
+        ..Default::default()
+    };
+    "daily".parse::<Sections>().unwrap().retain(&mut weather);
+    assert!(weather.current_weather.is_none());
+    assert_eq!(weather.wind_speed, 0.0);
+    assert!(weather.hour_forecasts.is_empty());
+    assert_eq!(weather.forecasts.len(), 1);
+}
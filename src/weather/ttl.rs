@@ -0,0 +1,185 @@
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+use crate::weather_proto::weather_message::{self, Conditions};
+
+/// How long a weather entry stays fresh, based on how quickly its conditions
+/// are likely to change. Volatile weather (storms, active alerts, rain starting
+/// or stopping within the hour) gets the shortest matching TTL; stable clear
+/// skies and daily-only responses, without minutely or hourly data, are kept
+/// longer.
+/// Anything else uses `cache.ttl_minutes`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct TtlPolicy {
+    pub(crate) enabled: bool,
+    pub(crate) storm_minutes: i64,
+    pub(crate) alert_minutes: i64,
+    pub(crate) precipitation_change_minutes: i64,
+    pub(crate) stable_clear_minutes: i64,
+    // hours of hourly forecast that must be clear for the skies to count as stable.
+    pub(crate) stable_hours: usize,
+    // entries fetched with only the daily forecast, see `Sections::daily_only`.
+    pub(crate) daily_only_minutes: i64,
+}
+
+impl Default for TtlPolicy {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            storm_minutes: 5,
+            alert_minutes: 10,
+            precipitation_change_minutes: 5,
+            stable_clear_minutes: 30,
+            stable_hours: 3,
+            daily_only_minutes: 60,
+        }
+    }
+}
+
+impl TtlPolicy {
+    /// The TTL in minutes for `weather` fetched at `now`, falling back to
+    /// `default_minutes` when no rule applies.
+    pub(crate) fn ttl_minutes(
+        &self,
+        weather: &weather_message::WeatherInfo,
+        default_minutes: i64,
+        now: DateTime<Utc>,
+    ) -> i64 {
+        if !self.enabled {
+            return default_minutes;
+        }
+        let volatile = [
+            (is_stormy(weather), self.storm_minutes),
+            (has_active_alert(weather, now), self.alert_minutes),
+            (
+                precipitation_changes(weather),
+                self.precipitation_change_minutes,
+            ),
+        ];
+        if let Some(minutes) = volatile
+            .iter()
+            .filter(|(applies, _)| *applies)
+            .map(|(_, minutes)| *minutes)
+            .min()
+        {
+            return minutes;
+        }
+        if weather.hour_forecasts.is_empty() && weather.minutely_rain.is_empty() {
+            return self.daily_only_minutes;
+        }
+        if self.is_stable_clear(weather) {
+            return self.stable_clear_minutes;
+        }
+        default_minutes
+    }
+
+    fn is_stable_clear(&self, weather: &weather_message::WeatherInfo) -> bool {
+        let clear = |condition: Conditions| condition == Conditions::CLEAR;
+        clear(weather.current_weather.condition.enum_value_or_default())
+            && weather.hour_forecasts.len() >= self.stable_hours
+            && weather.hour_forecasts[..self.stable_hours]
+                .iter()
+                .all(|hour| clear(hour.condition.enum_value_or_default()))
+            && weather
+                .minutely_rain
+                .iter()
+                .all(|minute| minute.rain <= 0.0)
+    }
+}
+
+// storm now or within the next couple of hours.
+fn is_stormy(weather: &weather_message::WeatherInfo) -> bool {
+    let storm = |condition: Conditions| condition == Conditions::STORM;
+    storm(weather.current_weather.condition.enum_value_or_default())
+        || weather
+            .hour_forecasts
+            .iter()
+            .take(2)
+            .any(|hour| storm(hour.condition.enum_value_or_default()))
+}
+
+fn has_active_alert(weather: &weather_message::WeatherInfo, now: DateTime<Utc>) -> bool {
+    let now = now.timestamp();
+    weather
+        .alerts
+        .iter()
+        .any(|alert| alert.start <= now && now <= alert.end)
+}
+
+// rain starts or stops somewhere in the minutely forecast.
+fn precipitation_changes(weather: &weather_message::WeatherInfo) -> bool {
+    weather
+        .minutely_rain
+        .windows(2)
+        .any(|pair| (pair[0].rain > 0.0) != (pair[1].rain > 0.0))
+}
+
+#[test]
+fn test_ttl_follows_volatility() {
+    use weather_message::{HourlyWeather, Minutely, WeatherAlert, WeatherInfo};
+
+    let hour = |condition: Conditions| HourlyWeather {
+        condition: condition.into(),
+        ..Default::default()
+    };
+    let minutes = |rain: &[f64]| {
+        rain.iter()
+            .map(|rain| Minutely {
+                rain: *rain,
+                ..Default::default()
+            })
+            .collect::<Vec<_>>()
+    };
+    let clear = WeatherInfo {
+        current_weather: Some(hour(Conditions::CLEAR)).into(),
+        hour_forecasts: vec![hour(Conditions::CLEAR); 4],
+        minutely_rain: minutes(&[0.0, 0.0]),
+        ..Default::default()
+    };
+    let policy = TtlPolicy::default();
+    let now = Utc::now();
+
+    assert_eq!(policy.ttl_minutes(&clear, 15, now), 30);
+
+    let cloudy = WeatherInfo {
+        current_weather: Some(hour(Conditions::CLOUDY)).into(),
+        ..clear.clone()
+    };
+    assert_eq!(policy.ttl_minutes(&cloudy, 15, now), 15);
+
+    let rain_starting = WeatherInfo {
+        minutely_rain: minutes(&[0.0, 0.4, 1.2]),
+        ..cloudy.clone()
+    };
+    assert_eq!(policy.ttl_minutes(&rain_starting, 15, now), 5);
+
+    let alert = WeatherInfo {
+        alerts: vec![WeatherAlert {
+            start: now.timestamp() - 60,
+            end: now.timestamp() + 3600,
+            ..Default::default()
+        }],
+        ..cloudy.clone()
+    };
+    assert_eq!(policy.ttl_minutes(&alert, 15, now), 10);
+
+    let storm = WeatherInfo {
+        hour_forecasts: vec![hour(Conditions::CLOUDY), hour(Conditions::STORM)],
+        ..alert.clone()
+    };
+    assert_eq!(policy.ttl_minutes(&storm, 15, now), 5);
+
+    let daily_only = WeatherInfo {
+        hour_forecasts: vec![],
+        minutely_rain: vec![],
+        ..cloudy
+    };
+    assert_eq!(policy.ttl_minutes(&daily_only, 15, now), 60);
+
+    let disabled = TtlPolicy {
+        enabled: false,
+        ..TtlPolicy::default()
+    };
+    assert_eq!(disabled.ttl_minutes(&storm, 15, now), 15);
+}
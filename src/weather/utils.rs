@@ -38,7 +38,6 @@ pub const EARTH_RADIUS_IN_KM: f64 = 6371.0;
 
 // location1 and location2 are [latitude, longitude].
 pub(crate) fn haversine(location1: &[f64; 2], location2: &[f64; 2]) -> f64 {
-    tracing::info!("location1: {:?}, location2: {:?}", location1, location2);
     let d_lat: f64 = (location2[0] - location1[0]).to_radians();
     let d_lon: f64 = (location2[1] - location1[1]).to_radians();
     let lat1: f64 = (location1[0]).to_radians();
@@ -51,9 +50,78 @@ pub(crate) fn haversine(location1: &[f64; 2], location2: &[f64; 2]) -> f64 {
     EARTH_RADIUS_IN_KM * c
 }
 
+/// `location` as a point on the unit sphere (ECEF with the earth's radius as
+/// the unit). Straight-line distances between these points grow with the
+/// great-circle distance, so a kd-tree over them prunes correctly everywhere,
+/// the antimeridian and poles included.
+pub(crate) fn unit_sphere_point(latitude: f64, longitude: f64) -> [f64; 3] {
+    let (latitude, longitude) = (latitude.to_radians(), longitude.to_radians());
+    [
+        latitude.cos() * longitude.cos(),
+        latitude.cos() * longitude.sin(),
+        latitude.sin(),
+    ]
+}
+
+/// The great-circle distance in km between unit sphere points given their
+/// squared straight-line distance, as kiddo's `squared_euclidean` returns.
+pub(crate) fn squared_chord_to_km(squared_chord: f64) -> f64 {
+    let half_chord = (squared_chord.sqrt() / 2.0).min(1.0);
+    2.0 * half_chord.asin() * EARTH_RADIUS_IN_KM
+}
+
+/// The inverse of `squared_chord_to_km`, for radius queries.
+pub(crate) fn km_to_squared_chord(km: f64) -> f64 {
+    let angle = (km / EARTH_RADIUS_IN_KM).min(std::f64::consts::PI);
+    (2.0 * (angle / 2.0).sin()).powi(2)
+}
+
+// the most `jitter` moves a point along each axis, about half a millimeter.
+const JITTER: f64 = 1e-10;
+
+/// `point` moved by a tiny offset derived from `seed`, for use as a kd-tree
+/// key. kiddo panics when every point in a full bucket shares the coordinate
+/// it splits on, as points on one latitude do, while jittered points with
+/// distinct seeds practically never share one.
+pub(crate) fn jitter(point: [f64; 3], seed: usize) -> [f64; 3] {
+    let mut state = seed as u64;
+    point.map(|axis| {
+        // splitmix64.
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        let unit = (z >> 11) as f64 / (1_u64 << 53) as f64;
+        axis + JITTER * (2.0 * unit - 1.0)
+    })
+}
+
 #[test]
 fn test_haversine_zero_dist() {
     let loc1 = [37.549521, -121.942765];
     let loc2 = [37.549521, -121.942765];
     assert_eq!(haversine(&loc1, &loc2), 0_f64);
 }
+
+#[test]
+fn test_chord_distance_matches_haversine() {
+    let squared_chord = |a: [f64; 2], b: [f64; 2]| {
+        kiddo::distance::squared_euclidean(
+            &unit_sphere_point(a[0], a[1]),
+            &unit_sphere_point(b[0], b[1]),
+        )
+    };
+    for (a, b) in [
+        ([37.5, -122.0], [37.6, -122.1]),
+        // across the antimeridian.
+        ([0.0, 179.95], [0.0, -179.95]),
+        // near the pole, where a degree of longitude is only meters wide.
+        ([89.99, 0.0], [89.99, 180.0]),
+        ([-45.0, 10.0], [45.0, -170.0]),
+    ] {
+        let km = squared_chord_to_km(squared_chord(a, b));
+        assert!((km - haversine(&a, &b)).abs() < 1e-6, "{a:?} {b:?}");
+        assert!((km_to_squared_chord(km) - squared_chord(a, b)).abs() < 1e-12);
+    }
+}
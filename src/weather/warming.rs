@@ -0,0 +1,221 @@
+use std::{cmp::Reverse, path::Path, time::Duration};
+
+use actix_web::web;
+use anyhow::{anyhow, bail, Context};
+use chrono::Utc;
+use tracing::{debug, info, warn};
+
+use crate::{
+    auth::ratelimit::{BucketConfig, RateLimiter},
+    cache::SpatialCache,
+    config::WarmingConfig,
+    entities::Location,
+    upstream,
+    validation::normalize_location,
+    AppState,
+};
+
+use super::{
+    entities::{CachedForecast, Units},
+    methods::refresh_weather,
+};
+
+/// A location the cache is kept warm for.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct HotLocation {
+    pub(crate) location: Location,
+    pub(crate) units: Units,
+    // higher priorities are refreshed first when the call cap is reached.
+    pub(crate) priority: i32,
+}
+
+/// Reads a file with one `latitude,longitude,units[,priority]` line per
+/// location. Blank lines and lines starting with `#` are skipped.
+pub(crate) fn read_locations_file(path: &Path) -> anyhow::Result<Vec<HotLocation>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("could not read {}", path.display()))?;
+    parse_locations(&contents).with_context(|| format!("invalid locations in {}", path.display()))
+}
+
+fn parse_locations(contents: &str) -> anyhow::Result<Vec<HotLocation>> {
+    let mut locations = vec![];
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        let (latitude, longitude, units, priority) = match fields.as_slice() {
+            [lat, lon, units] => (lat, lon, units, "0"),
+            [lat, lon, units, priority] => (lat, lon, units, *priority),
+            _ => bail!(
+                "line {}: expected latitude,longitude,units[,priority]",
+                number + 1
+            ),
+        };
+        let parse = |value: &str, what: &str| {
+            value
+                .parse::<f64>()
+                .with_context(|| format!("line {}: invalid {} {:?}", number + 1, what, value))
+        };
+        locations.push(HotLocation {
+            location: normalize_location(
+                parse(latitude, "latitude")?,
+                parse(longitude, "longitude")?,
+            )
+            .map_err(|err| anyhow!("line {}: {}", number + 1, err))?,
+            units: units
+                .parse()
+                .with_context(|| format!("line {}", number + 1))?,
+            priority: priority
+                .parse()
+                .with_context(|| format!("line {}: invalid priority {:?}", number + 1, priority))?,
+        });
+    }
+    Ok(locations)
+}
+
+/// Keeps the weather entries of `locations` fresh by refreshing each one
+/// shortly before it expires, highest priority first, within the configured
+/// call cap. The AQI and reverse geocode tiers are left to requests.
+pub(crate) async fn keep_warm(
+    data: web::Data<AppState>,
+    mut locations: Vec<HotLocation>,
+    config: WarmingConfig,
+) {
+    // the sort is stable, so equal priorities keep their order in the file.
+    locations.sort_by_key(|hot| Reverse(hot.priority));
+    let cap = f64::from(config.max_refreshes_per_minute);
+    let limiter = RateLimiter::new(BucketConfig {
+        capacity: cap,
+        refill_per_sec: cap / 60.0,
+    });
+    let lead = chrono::Duration::seconds(config.refresh_before_secs as i64);
+    info!("Keeping {} locations warm", locations.len());
+    loop {
+        for hot in due_locations(&data.caches.weather, &locations, lead) {
+            if limiter.try_take("warming", 1.0).is_err() {
+                debug!("Cache warming reached its call cap, deferring the rest");
+                break;
+            }
+            match refresh_weather(hot.location, hot.units, &data).await {
+                Ok(_) => debug!(
+                    "Warmed {}, {} ({})",
+                    hot.location.latitude, hot.location.longitude, hot.units
+                ),
+                // the breaker or budget is holding calls back, try again next round.
+                Err(err) if upstream::is_unavailable(&err) => {
+                    debug!("Skipping cache warming: {}", err);
+                    break;
+                }
+                Err(err) => warn!(
+                    "Could not warm {}, {}: {:#}",
+                    hot.location.latitude, hot.location.longitude, err
+                ),
+            }
+        }
+        tokio::time::sleep(Duration::from_secs(config.check_interval_secs)).await;
+    }
+}
+
+// the locations whose entry in their units is missing or expires within
+// `lead`, in order.
+fn due_locations<'a>(
+    cache: &SpatialCache<CachedForecast>,
+    locations: &'a [HotLocation],
+    lead: chrono::Duration,
+) -> Vec<&'a HotLocation> {
+    let refresh_by = Utc::now() + lead;
+    locations
+        .iter()
+        .filter(|hot| {
+            cache
+                .find(&hot.location, |forecast| forecast.serves(hot.units, false))
+                .and_then(|(id, _)| cache.entry(id))
+                .is_none_or(|entry| entry.expiry <= refresh_by)
+        })
+        .collect()
+}
+
+#[test]
+fn test_parse_locations() {
+    let locations = parse_locations(
+        "# city centres\n\
+         40.7128, -74.0060, imperial, 10\n\
+         \n\
+         51.5074,-0.1278,metric\n",
+    )
+    .unwrap();
+    assert_eq!(
+        locations,
+        vec![
+            HotLocation {
+                location: Location::at(40.7128, -74.006),
+                units: Units::Imperial,
+                priority: 10,
+            },
+            HotLocation {
+                location: Location::at(51.5074, -0.1278),
+                units: Units::Metric,
+                priority: 0,
+            },
+        ]
+    );
+    assert!(parse_locations("40.7,-74.0").is_err());
+    assert!(parse_locations("north,-74.0,metric").is_err());
+    assert!(parse_locations("40.7,-74.0,kelvin").is_err());
+    assert!(parse_locations("140.7,-74.0,metric").is_err());
+}
+
+#[test]
+fn test_due_locations_by_units() {
+    use crate::cache::{CacheIndex, Tier, TierConfig};
+
+    let cache = SpatialCache::new(
+        "weather",
+        TierConfig {
+            radius_km: 10.0,
+            ttl: chrono::Duration::minutes(15),
+            stale_grace: chrono::Duration::hours(24),
+            index: CacheIndex::KdTree,
+            geohash_precision: 5,
+            shards: 4,
+        },
+        None,
+    );
+    let location = Location::at(40.7128, -74.006);
+    let hot = |units| HotLocation {
+        location,
+        units,
+        priority: 0,
+    };
+    let locations = [hot(Units::Metric), hot(Units::Imperial)];
+    let lead = chrono::Duration::seconds(60);
+    let forecast = |units| CachedForecast {
+        weather: Default::default(),
+        units,
+        daily_only: false,
+    };
+
+    cache.insert(
+        location,
+        forecast(Units::Metric),
+        chrono::Duration::minutes(15),
+        None,
+    );
+    assert_eq!(
+        due_locations(&cache, &locations, lead),
+        vec![&hot(Units::Imperial)]
+    );
+    // refreshing the imperial entry leaves the metric one alone.
+    for _ in 0..3 {
+        cache.insert(
+            location,
+            forecast(Units::Imperial),
+            chrono::Duration::minutes(15),
+            None,
+        );
+        assert!(due_locations(&cache, &locations, lead).is_empty());
+    }
+    assert_eq!(cache.len(), 2);
+}